@@ -1,96 +1,433 @@
-use actix_web::{rt::time::sleep, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
-use reqwest::Client;
+use actix_web::{
+    body::MessageBody,
+    dev::{Extensions, ServiceFactory, ServiceRequest, ServiceResponse},
+    error::{InternalError, JsonPayloadError, QueryPayloadError},
+    http::{header, StatusCode},
+    middleware::{from_fn, Compress, Next},
+    rt::time::sleep,
+    web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder, ResponseError,
+};
+use flate2::{write::GzEncoder, Compression};
+use reqwest::{Client, Method};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use sha2::{Digest, Sha256};
+use std::collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex as AsyncMutex, OnceCell as AsyncOnceCell, Semaphore, SemaphorePermit};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
 
 /// Data structure for an incoming question.
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 struct AskRequest {
     /// User's question.
     query: String,
-    /// Number of documents to retrieve (optional).
+    /// Number of documents to retrieve (optional, defaults to `GATEWAY_DEFAULT_TOP_K`
+    /// or 4; clamped to 1..=`GATEWAY_MAX_TOP_K`, 20 unless configured).
     #[serde(default)]
+    #[schema(minimum = 1)]
     top_k: Option<u8>,
+    /// Model the backend should answer with (optional; may default from the key profile).
+    #[serde(default)]
+    model: Option<String>,
+    /// Answer language (optional; may default from the key profile).
+    #[serde(default)]
+    language: Option<String>,
+    /// Conversation id for multi-turn context (optional, non-empty, bounded length).
+    #[serde(default)]
+    session_id: Option<String>,
+    /// Generation seed for reproducible answers (optional; absent means backend default).
+    #[serde(default)]
+    #[schema(maximum = 9_007_199_254_740_991_u64)]
+    seed: Option<u64>,
+    /// Upper bound on the generated answer length in characters (optional;
+    /// clamped to the gateway-wide cap).
+    #[serde(default)]
+    max_answer_chars: Option<u32>,
+    /// Whether the backend should rerank retrieved chunks with its cross-encoder
+    /// (optional; only forwarded when set, so omitting it keeps the backend default).
+    #[serde(default)]
+    rerank: Option<bool>,
+    /// Metadata constraints restricting retrieval, e.g. `{"source": "playbook"}`
+    /// (optional; bounded like `add_doc` metadata).
+    #[serde(default)]
+    filter: Option<HashMap<String, String>>,
 }
 
 /// Data structure for the response returned by the AI layer.
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 struct AskResponse {
+    /// Identifier of this ask (also sent as `X-Request-ID`); pass it to
+    /// `/api/feedback` to rate the answer.
+    request_id: String,
     /// Final answer text.
     answer: String,
     /// List of citations augmented with retrieved text.
     citations: Vec<Citation>,
     /// Total latency in milliseconds.
     latency_ms: u128,
+    /// Set when the answer is the configured fallback because Python was unreachable.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    degraded: bool,
+    /// Echo of the request's `session_id`, when one was supplied.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+    /// Number of duplicate citations removed (only present when `DEDUP_CITATIONS=true`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    citations_deduped: Option<usize>,
+    /// Set when `GATEWAY_DRY_RUN` produced a canned answer without calling Python.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+    /// Model that produced the answer, as reported by the Python backend.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    /// Python backend version that produced the answer, when reported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend_version: Option<String>,
 }
 
 /// Citation payload shared between the Rust gateway and Python backend.
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 struct Citation {
     source: String,
     text: String,
 }
 
 /// Shape of the response returned by the Python FastAPI service.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 struct PythonAskResponse {
     answer: String,
     citations: Vec<Citation>,
+    /// Model that produced the answer, when the backend reports it.
+    #[serde(default)]
+    model: Option<String>,
+    /// Backend build or release identifier, when reported.
+    #[serde(default)]
+    backend_version: Option<String>,
 }
 
 /// Payload for ingesting documents.
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 struct AddDocRequest {
+    /// Document text to embed and store.
     text: String,
+    /// Optional string metadata (key count and lengths are bounded by the gateway).
     #[serde(default)]
     metadata: Option<HashMap<String, String>>,
 }
 
 /// Response returned when a document is added through the gateway.
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 struct AddDocResponse {
     document_id: String,
     latency_ms: u128,
+    /// Set when `GATEWAY_DRY_RUN` produced a synthetic document id without calling Python.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
 }
 
 /// Shape of the Python add document response.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 struct PythonAddDocResponse {
     document_id: String,
 }
 
+/// Response returned when a document is validated through the gateway.
+#[derive(Serialize)]
+struct ValidateDocResponse {
+    /// Whether the backend would accept the document for ingestion.
+    valid: bool,
+    /// Backend-reported problems (duplicates, size limits, ...).
+    issues: Vec<String>,
+    latency_ms: u128,
+}
+
+/// Shape of the Python validate document verdict.
+#[derive(Deserialize)]
+struct PythonValidateDocResponse {
+    valid: bool,
+    #[serde(default)]
+    issues: Vec<String>,
+}
+
+/// Pagination parameters for listing ingested documents.
+#[derive(Deserialize)]
+struct ListDocsQuery {
+    /// Page size (optional, defaults to 50; clamped to the configured maximum).
+    #[serde(default)]
+    limit: Option<i64>,
+    /// Number of documents to skip (optional, must not be negative).
+    #[serde(default)]
+    offset: Option<i64>,
+}
+
+/// One stored document as reported by the backend.
+#[derive(Deserialize, Serialize)]
+struct ListedDocument {
+    document_id: String,
+    #[serde(default)]
+    metadata: HashMap<String, Value>,
+}
+
+/// Response returned when listing documents through the gateway.
+#[derive(Serialize)]
+struct ListDocsResponse {
+    documents: Vec<ListedDocument>,
+    limit: u64,
+    offset: u64,
+    latency_ms: u128,
+}
+
+/// Shape of the Python list documents response.
+#[derive(Deserialize)]
+struct PythonListDocsResponse {
+    documents: Vec<ListedDocument>,
+}
+
+/// Payload for computing raw embeddings.
+#[derive(Deserialize, Serialize)]
+struct EmbedRequest {
+    /// Texts to embed (non-empty, bounded count and length).
+    texts: Vec<String>,
+}
+
+/// Response returned when texts are embedded through the gateway.
+#[derive(Serialize)]
+struct EmbedResponse {
+    /// One vector per input text, in request order.
+    embeddings: Vec<Vec<f64>>,
+    latency_ms: u128,
+}
+
+/// Shape of the Python embed response.
+#[derive(Deserialize)]
+struct PythonEmbedResponse {
+    embeddings: Vec<Vec<f64>>,
+}
+
+/// Body of `POST /api/admin/mode`.
+#[derive(Deserialize)]
+struct ModeRequest {
+    /// Whether ingestion should be rejected.
+    read_only: bool,
+}
+
+/// Answer-quality signal sent by a client about an earlier ask.
+#[derive(Deserialize, Serialize)]
+struct FeedbackRequest {
+    /// Identifier of the ask being rated: the `request_id` returned by `/api/ask`.
+    request_id: String,
+    rating: FeedbackRating,
+    /// Free-text remark (optional, bounded length, never logged).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+/// Thumbs-up / thumbs-down rating for an answer.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum FeedbackRating {
+    Up,
+    Down,
+}
+
+impl FeedbackRating {
+    fn as_str(self) -> &'static str {
+        match self {
+            FeedbackRating::Up => "up",
+            FeedbackRating::Down => "down",
+        }
+    }
+}
+
+/// Shape of every gateway error body.  `field` and `constraint` are present on
+/// validation failures; upstream failures carry `upstream_status`/`last_error`,
+/// and rejections passed through from Python carry `upstream_error`.
+#[derive(ToSchema)]
+#[allow(dead_code)] // Documentation only: error bodies are built with `json!`.
+struct ErrorResponse {
+    error: String,
+    field: Option<String>,
+    constraint: Option<String>,
+    upstream_status: Option<u16>,
+    last_error: Option<String>,
+    #[schema(value_type = Option<Object>)]
+    upstream_error: Option<Value>,
+    schema_version: String,
+}
+
+/// OpenAPI description of the public gateway endpoints, served at `/openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "EdgeLink gateway",
+        description = "Rust gateway in front of the Python RAG service. Every JSON body carries `schema_version`."
+    ),
+    paths(ask_handler, add_doc_handler),
+    components(schemas(AskRequest, AskResponse, Citation, AddDocRequest, AddDocResponse, ErrorResponse)),
+    modifiers(&ApiKeySecurity)
+)]
+struct ApiDoc;
+
+/// Registers the `X-API-KEY` header scheme referenced by the path annotations.
+struct ApiKeySecurity;
+
+impl Modify for ApiKeySecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "api_key",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+            );
+    }
+}
+
+/// Version of the response contract, stamped on every JSON body as
+/// `schema_version`.  Bump deliberately when response shapes change.
+const SCHEMA_VERSION: &str = "1";
+/// Environment variable prefixes that make up the gateway's effective config.
+const CONFIG_ENV_PREFIXES: [&str; 10] = [
+    "GATEWAY_",
+    "PYTHON_",
+    "BACKEND_",
+    "RUST_API_",
+    "TLS_",
+    "CITATION_",
+    "DEDUP_",
+    "FAILOVER_",
+    "ERROR_BODY_",
+    "STAMP_",
+];
+const SWAGGER_UI_HTML: &str = r##"<!doctype html>
+<html>
+  <head>
+    <title>EdgeLink gateway API</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+  </head>
+  <body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+      SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+    </script>
+  </body>
+</html>
+"##;
 const DEFAULT_TOP_K: u8 = 4;
 const MAX_TOP_K: u8 = 20;
+const DEFAULT_MAX_ANSWER_CHARS_CAP: u32 = 8_000;
 const PYTHON_DEFAULT_URL: &str = "http://127.0.0.1:8001";
 const PYTHON_ASK_ENDPOINT: &str = "/ask";
 const PYTHON_ADD_DOC_ENDPOINT: &str = "/add_doc";
+const PYTHON_VALIDATE_DOC_ENDPOINT: &str = "/validate_doc";
+const PYTHON_HEALTH_ENDPOINT: &str = "/health";
+const PYTHON_FEEDBACK_ENDPOINT: &str = "/feedback";
+const PYTHON_EMBED_ENDPOINT: &str = "/embed";
+const PYTHON_LIST_DOCS_ENDPOINT: &str = "/list_docs";
+const DEFAULT_LIST_DOCS_LIMIT: u64 = 50;
+const DEFAULT_MAX_LIST_DOCS_LIMIT: u64 = 200;
+const DEFAULT_HEALTH_PROBE_CACHE_MS: u64 = 5_000;
+const DEFAULT_HEALTH_PROBE_TIMEOUT_MS: u64 = 1_000;
 const API_KEY_HEADER: &str = "X-API-KEY";
+const APP_ID_HEADER: &str = "X-App-ID";
+const DEADLINE_HEADER: &str = "X-Deadline-Ms";
+const SIGNATURE_HEADER: &str = "x-signature";
+const BACKEND_QUEUE_DEPTH_HEADER: &str = "X-Backend-Queue-Depth";
+const BACKEND_QUEUE_DEPTH_TTL_MS: u64 = 2_000;
+const MAX_APP_ID_LEN: usize = 64;
+const MAX_CLIENT_IP_LEN: usize = 64;
+const MAX_USER_AGENT_LOG_LEN: usize = 256;
+const DEFAULT_MAX_TRACKED_APPS: usize = 100;
+const APP_OVERFLOW_BUCKET: &str = "__overflow__";
+const RATE_LIMIT_EVICTION_SECS: u64 = 60;
+const QUEUE_WAIT_SAMPLES: usize = 1024;
+const DISCONNECT_POLL_MS: u64 = 100;
+/// Non-standard status (nginx convention) for requests abandoned by the client.
+const CLIENT_CLOSED_REQUEST: u16 = 499;
+const SUPPORTED_MEDIA_TYPES: [&str; 3] = ["application/json", "text/plain", "text/event-stream"];
+const ADMIN_KEY_HEADER: &str = "X-ADMIN-KEY";
+const DEFAULT_CANARY_QUERY: &str = "What does the gateway do?";
+const DEFAULT_CANARY_MAX_LATENCY_MS: u64 = 5_000;
+const DRY_RUN_ANSWER: &str = "Dry run: the Python service was not called.";
+const DEFAULT_WARMUP_DEADLINE_SECS: u64 = 60;
+const DEFAULT_WARMUP_INTERVAL_MS: u64 = 1_000;
+const BYPASS_CACHE_HEADER: &str = "X-Bypass-Cache";
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const DEFAULT_IDEMPOTENCY_TTL_MS: u64 = 60 * 60 * 1000;
+const DEFAULT_ASK_CACHE_MAX_ENTRIES: usize = 1024;
+const DEFAULT_ERROR_BODY_LOG_CHARS: usize = 256;
+const MAX_SESSION_ID_LEN: usize = 128;
+/// Length of the hex identifiers minted by `new_request_id`.
+const REQUEST_ID_LEN: usize = 32;
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_FEEDBACK_COMMENT_LEN: usize = 2_000;
+const MAX_EMBED_TEXTS: usize = 64;
+const MAX_EMBED_TEXT_LEN: usize = 8_192;
+/// Largest seed that survives a round-trip through JSON number parsers (2^53 - 1).
+const MAX_SEED: u64 = (1 << 53) - 1;
+const DEFAULT_MAX_METADATA_KEYS: usize = 32;
+const DEFAULT_MAX_METADATA_KEY_LEN: usize = 64;
+const DEFAULT_MAX_METADATA_VALUE_LEN: usize = 1024;
 const MAX_RETRIES: usize = 3;
 const BASE_BACKOFF_MS: u64 = 120;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 5_000;
 
 /// Extracts the API key and validates that it is present and non-empty.
+///
+/// `X-API-KEY` is checked first; when it is absent the key may instead be sent
+/// as `Authorization: Bearer <token>`.
 fn extract_api_key(req: &HttpRequest) -> Result<String, HttpResponse> {
-    match req.headers().get(API_KEY_HEADER) {
-        Some(value) => {
-            let api_key = value.to_str().unwrap_or("").trim();
-            if api_key.is_empty() {
-                log_gateway_event(
-                    "gateway.auth_failed",
-                    json!({
-                        "path": req.path(),
-                        "method": req.method().as_str(),
-                        "reason": "empty"
-                    }),
-                );
-                Err(HttpResponse::Unauthorized().json(json!({
-                    "error": "X-API-KEY header must not be empty"
-                })))
-            } else {
-                Ok(api_key.to_owned())
-            }
+    let (scheme, api_key) = match req.headers().get(API_KEY_HEADER) {
+        Some(value) => (
+            "x-api-key",
+            Some(value.to_str().unwrap_or("").trim().to_owned()),
+        ),
+        None => ("bearer", bearer_token(req)),
+    };
+    match api_key {
+        Some(api_key) if !api_key.is_empty() && api_key_allowed(&api_key) => Ok(api_key),
+        Some(api_key) if !api_key.is_empty() => {
+            log_gateway_event(
+                "gateway.auth_failed",
+                json!({
+                    "path": req.path(),
+                    "method": req.method().as_str(),
+                    "reason": "unknown_key",
+                    "scheme": scheme
+                }),
+            );
+            Err(versioned_json(
+                HttpResponse::Unauthorized(),
+                json!({
+                    "error": "Invalid API key"
+                }),
+            ))
+        }
+        Some(_) => {
+            log_gateway_event(
+                "gateway.auth_failed",
+                json!({
+                    "path": req.path(),
+                    "method": req.method().as_str(),
+                    "reason": "empty",
+                    "scheme": scheme
+                }),
+            );
+            Err(versioned_json(
+                HttpResponse::Unauthorized(),
+                json!({
+                    "error": "API key must not be empty"
+                }),
+            ))
         }
         None => {
             log_gateway_event(
@@ -101,244 +438,5727 @@ fn extract_api_key(req: &HttpRequest) -> Result<String, HttpResponse> {
                     "reason": "missing"
                 }),
             );
-            Err(HttpResponse::Unauthorized().json(json!({
-                "error": "Missing X-API-KEY header"
-            })))
+            Err(versioned_json(
+                HttpResponse::Unauthorized(),
+                json!({
+                    "error": "Missing X-API-KEY header or Authorization: Bearer token"
+                }),
+            ))
         }
     }
 }
 
-/// Pretty-print structured gateway logs.
-fn log_gateway_event(event: &str, details: Value) {
-    let log_entry = json!({
-        "event": event,
-        "details": details,
-    });
-    match serde_json::to_string_pretty(&log_entry) {
-        Ok(pretty) => println!("{}", pretty),
-        Err(_) => println!("{}", log_entry),
+/// SHA-256 digests of the configured API keys, loaded once on first use.
+///
+/// `None` means no allowlist is configured and any non-empty key is accepted.
+static API_KEY_ALLOWLIST: OnceLock<Option<Vec<[u8; 32]>>> = OnceLock::new();
+
+/// Loads the allowlist from `GATEWAY_API_KEYS` (comma-separated) and/or
+/// `GATEWAY_API_KEYS_FILE` (one key per line, `#` comments allowed).
+fn load_api_key_allowlist() -> Option<Vec<[u8; 32]>> {
+    let mut keys: Vec<String> = Vec::new();
+    if let Ok(inline) = std::env::var("GATEWAY_API_KEYS") {
+        keys.extend(inline.split(',').map(|key| key.trim().to_owned()));
+    }
+    if let Ok(path) = std::env::var("GATEWAY_API_KEYS_FILE") {
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => keys.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.starts_with('#'))
+                    .map(str::to_owned),
+            ),
+            Err(err) => log_gateway_event(
+                "gateway.config",
+                json!({
+                    "setting": "GATEWAY_API_KEYS_FILE",
+                    "warning": format!("failed to read API key file {}: {}", path, err),
+                }),
+            ),
+        }
     }
+    keys.retain(|key| !key.is_empty());
+    if keys.is_empty() {
+        return None;
+    }
+    Some(
+        keys.iter()
+            .map(|key| Sha256::digest(key.as_bytes()).into())
+            .collect(),
+    )
 }
 
-/// Helper to compute the python service base url from the environment.
-fn python_service_base_url() -> String {
-    std::env::var("PYTHON_AI_URL").unwrap_or_else(|_| PYTHON_DEFAULT_URL.to_string())
+/// Checks a key against the allowlist (if any) in constant time.
+///
+/// Keys are compared by SHA-256 digest so neither the key contents nor their
+/// lengths influence timing, and every allowlisted entry is always examined.
+fn api_key_allowed(api_key: &str) -> bool {
+    let Some(allowlist) = API_KEY_ALLOWLIST.get_or_init(load_api_key_allowlist) else {
+        return true;
+    };
+    let digest: [u8; 32] = Sha256::digest(api_key.as_bytes()).into();
+    allowlist.iter().fold(false, |found, allowed| {
+        found | constant_time_eq(allowed, &digest)
+    })
 }
 
-/// Sends a JSON payload to the Python backend with retry and exponential backoff.
-async fn post_with_retry<T, U>(
-    client: &Client,
-    endpoint: &str,
-    payload: &T,
-) -> Result<U, HttpResponse>
-where
-    T: Serialize,
-    U: DeserializeOwned,
-{
-    let mut last_status: Option<u16> = None;
-    let mut last_error: Option<String> = None;
-    let base_url = python_service_base_url();
-    let url = format!("{}{}", base_url.trim_end_matches('/'), endpoint);
-
-    for attempt in 0..MAX_RETRIES {
-        match client.post(&url).json(payload).send().await {
-            Ok(resp) => {
-                let status = resp.status();
-                if status.is_server_error() && attempt + 1 < MAX_RETRIES {
-                    last_status = Some(status.as_u16());
-                    log_gateway_event(
-                        "gateway.retry",
-                        json!({
-                            "url": url,
-                            "attempt": attempt + 1,
-                            "status": status.as_u16(),
-                            "reason": "upstream_server_error"
-                        }),
-                    );
-                    sleep(Duration::from_millis(BASE_BACKOFF_MS * (1 << attempt))).await;
-                    continue;
-                }
+/// Per-instance counter stamped on every log event as `seq`, giving a total
+/// order for log replay even when timestamps tie.
+static LOG_SEQUENCE: AtomicU64 = AtomicU64::new(0);
 
-                match resp.json::<U>().await {
-                    Ok(body) => return Ok(body),
-                    Err(err) => {
-                        last_status = Some(status.as_u16());
-                        last_error = Some(format!("Failed to parse upstream response: {}", err));
-                        break;
-                    }
-                }
-            }
-            Err(err) => {
-                last_error = Some(err.to_string());
-                if attempt + 1 < MAX_RETRIES {
-                    log_gateway_event(
-                        "gateway.retry",
-                        json!({
-                            "url": url,
-                            "attempt": attempt + 1,
-                            "status": "network_error",
-                            "error": err.to_string()
-                        }),
-                    );
-                    sleep(Duration::from_millis(BASE_BACKOFF_MS * (1 << attempt))).await;
-                    continue;
-                } else {
-                    break;
-                }
-            }
-        }
-    }
+/// Random per-process seed and counter behind `new_request_id`.
+static REQUEST_ID_SEED: OnceLock<u64> = OnceLock::new();
+static REQUEST_ID_SEQUENCE: AtomicU64 = AtomicU64::new(0);
 
-    Err(HttpResponse::BadGateway().json(json!({
-        "error": "Failed to reach Python service",
-        "upstream_status": last_status,
-        "last_error": last_error,
-    })))
+/// Mints an identifier for an ask: `REQUEST_ID_LEN` lowercase hex characters,
+/// unique within the process and not guessable across processes.
+fn new_request_id() -> String {
+    let seed = REQUEST_ID_SEED.get_or_init(|| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_nanos()),
+        );
+        hasher.finish()
+    });
+    let sequence = REQUEST_ID_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let digest = Sha256::new()
+        .chain_update(seed.to_le_bytes())
+        .chain_update(sequence.to_le_bytes())
+        .finalize();
+    format!("{:x}", digest)[..REQUEST_ID_LEN].to_string()
 }
 
-/// Handler for the `/api/ask` endpoint.
-async fn ask_handler(
-    http_req: HttpRequest,
-    client: web::Data<Client>,
-    req: web::Json<AskRequest>,
-) -> impl Responder {
-    let api_key = match extract_api_key(&http_req) {
-        Ok(key) => key,
-        Err(resp) => return resp,
-    };
+/// Whether `id` has the shape of an id minted by `new_request_id`.
+fn is_request_id(id: &str) -> bool {
+    id.len() == REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+}
 
-    let top_k = req.top_k.unwrap_or(DEFAULT_TOP_K).clamp(1, MAX_TOP_K);
-    let start = Instant::now();
+/// Reads the token from an `Authorization: Bearer <token>` header, if present.
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.trim().split_once(' ').unwrap_or((value.trim(), ""));
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim().to_owned())
+}
 
-    let query = req.query.clone();
-    let query_length = query.len();
-    let payload = json!({
-        "query": query,
-        "top_k": top_k
-    });
+/// Maintenance switch: while read-only, ingestion is rejected with `503` and
+/// asks keep working.  Starts from `GATEWAY_READ_ONLY`; `POST /api/admin/mode`
+/// overrides it at runtime until the process restarts.
+struct GatewayMode {
+    read_only: AtomicBool,
+}
 
-    match post_with_retry::<_, PythonAskResponse>(&client, PYTHON_ASK_ENDPOINT, &payload).await {
-        Ok(body) => {
-            let latency_ms = start.elapsed().as_millis();
-            let response = HttpResponse::Ok().json(AskResponse {
-                answer: body.answer,
-                citations: body.citations,
-                latency_ms,
-            });
-            log_gateway_event(
-                "gateway.request",
-                json!({
-                    "path": "/api/ask",
-                    "method": "POST",
-                    "status": response.status().as_u16(),
-                    "latency_ms": latency_ms,
-                    "api_key_present": !api_key.is_empty(),
-                    "request": {
-                        "query_length": query_length,
-                        "top_k": top_k,
-                    }
-                }),
-            );
-            response
+impl GatewayMode {
+    fn from_env() -> Self {
+        GatewayMode {
+            read_only: AtomicBool::new(env_flag("GATEWAY_READ_ONLY")),
         }
-        Err(resp) => {
-            let latency_ms = start.elapsed().as_millis();
-            log_gateway_event(
-                "gateway.request",
-                json!({
-                    "path": "/api/ask",
-                    "method": "POST",
-                    "status": resp.status().as_u16(),
-                    "latency_ms": latency_ms,
-                    "api_key_present": !api_key.is_empty(),
-                }),
-            );
-            resp
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Rejects a write with `503` while the gateway is read-only.
+    fn reject_writes(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        if !self.read_only() {
+            return Ok(());
         }
+        log_gateway_event(
+            "gateway.read_only_rejected",
+            json!({
+                "path": req.path(),
+                "method": req.method().as_str(),
+            }),
+        );
+        Err(versioned_json(
+            HttpResponse::ServiceUnavailable(),
+            json!({ "error": "gateway is in read-only mode" }),
+        ))
     }
 }
 
-/// Handler to forward document ingestion to the Python backend.
-async fn add_doc_handler(
-    http_req: HttpRequest,
-    client: web::Data<Client>,
-    req: web::Json<AddDocRequest>,
-) -> impl Responder {
-    let api_key = match extract_api_key(&http_req) {
-        Ok(key) => key,
-        Err(resp) => return resp,
+/// Guards admin endpoints: the `X-ADMIN-KEY` header must match `GATEWAY_ADMIN_KEY`.
+///
+/// Admin endpoints are disabled (`403`) when no admin key is configured.
+fn require_admin(req: &HttpRequest) -> Result<(), HttpResponse> {
+    let configured = std::env::var("GATEWAY_ADMIN_KEY")
+        .ok()
+        .filter(|key| !key.trim().is_empty());
+    let provided = req
+        .headers()
+        .get(ADMIN_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .unwrap_or("");
+    let reason = match configured {
+        None => "admin_disabled",
+        Some(_) if provided.is_empty() => "missing",
+        Some(key) if !constant_time_eq(key.trim().as_bytes(), provided.as_bytes()) => "unknown_key",
+        Some(_) => return Ok(()),
     };
+    log_gateway_event(
+        "gateway.admin_auth_failed",
+        json!({
+            "path": req.path(),
+            "method": req.method().as_str(),
+            "reason": reason,
+        }),
+    );
+    Err(versioned_json(
+        HttpResponse::Forbidden(),
+        json!({ "error": "Admin access requires a valid X-ADMIN-KEY header" }),
+    ))
+}
 
-    let start = Instant::now();
-    let metadata_keys: Vec<String> = req
-        .metadata
-        .as_ref()
-        .map(|map| map.keys().cloned().collect())
-        .unwrap_or_else(Vec::new);
-    let text_length = req.text.len();
+/// Compares two byte strings without short-circuiting on the first mismatch.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
 
-    match post_with_retry::<_, PythonAddDocResponse>(&client, PYTHON_ADD_DOC_ENDPOINT, &*req).await
-    {
-        Ok(body) => {
-            let latency_ms = start.elapsed().as_millis();
-            let response = HttpResponse::Ok().json(AddDocResponse {
-                document_id: body.document_id,
-                latency_ms,
-            });
-            log_gateway_event(
-                "gateway.request",
-                json!({
-                    "path": "/api/add_doc",
-                    "method": "POST",
-                    "status": response.status().as_u16(),
-                    "latency_ms": latency_ms,
-                    "api_key_present": !api_key.is_empty(),
-                    "request": {
-                        "text_length": text_length,
-                        "metadata_keys": metadata_keys,
-                    }
-                }),
-            );
-            response
+/// Serializes `body` into the response, stamping it with `schema_version` and,
+/// when `STAMP_RESPONSE_META` is enabled, the `meta` object from `response_meta`.
+fn versioned_json<T: Serialize>(mut builder: HttpResponseBuilder, body: T) -> HttpResponse {
+    let mut value = serde_json::to_value(body).unwrap_or_else(|_| json!({}));
+    if let Value::Object(map) = &mut value {
+        map.insert("schema_version".to_string(), json!(SCHEMA_VERSION));
+        if let Some(meta) = RESPONSE_META.get_or_init(response_meta) {
+            map.insert("meta".to_string(), meta.clone());
         }
-        Err(resp) => {
-            let latency_ms = start.elapsed().as_millis();
-            log_gateway_event(
-                "gateway.request",
-                json!({
-                    "path": "/api/add_doc",
-                    "method": "POST",
-                    "status": resp.status().as_u16(),
-                    "latency_ms": latency_ms,
-                    "api_key_present": !api_key.is_empty(),
-                }),
-            );
-            resp
+    }
+    builder.json(value)
+}
+
+/// Instance details stamped on responses for support tickets, computed once.
+static RESPONSE_META: OnceLock<Option<Value>> = OnceLock::new();
+
+/// Builds the `meta` object (gateway version, `GATEWAY_INSTANCE_ID`,
+/// `GATEWAY_REGION` and a hash of the effective configuration), or `None`
+/// unless `STAMP_RESPONSE_META` is enabled.
+fn response_meta() -> Option<Value> {
+    if !env_flag("STAMP_RESPONSE_META") {
+        return None;
+    }
+    Some(json!({
+        "gateway_version": env!("CARGO_PKG_VERSION"),
+        "instance_id": std::env::var("GATEWAY_INSTANCE_ID").ok(),
+        "region": std::env::var("GATEWAY_REGION").ok(),
+        "config_hash": config_hash(),
+    }))
+}
+
+/// Short SHA-256 over the gateway's configuration variables (sorted
+/// `NAME=value` pairs).  Anything whose name mentions a key or secret is
+/// skipped so the hash never depends on credentials.
+fn config_hash() -> String {
+    let mut settings: Vec<(String, String)> = std::env::vars()
+        .filter(|(name, _)| {
+            CONFIG_ENV_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+                && !name.contains("KEY")
+                && !name.contains("SECRET")
+        })
+        .collect();
+    settings.sort();
+    let mut hasher = Sha256::new();
+    for (name, value) in settings {
+        hasher.update(name.as_bytes());
+        hasher.update(b"=");
+        hasher.update(value.as_bytes());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())[..16].to_string()
+}
+
+/// Converts `web::Json` extraction failures into the standard `{"error": ...}`
+/// shape instead of Actix's plain-text default.
+fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    let (reason, message) = match &err {
+        JsonPayloadError::Deserialize(inner) if inner.is_data() => {
+            ("invalid_field", format!("Invalid request body: {}", inner))
         }
+        JsonPayloadError::Deserialize(inner) => (
+            "malformed_json",
+            format!("Request body is not valid JSON: {}", inner),
+        ),
+        JsonPayloadError::ContentType => (
+            "content_type",
+            "Content-Type must be application/json".to_string(),
+        ),
+        other => ("payload", other.to_string()),
+    };
+    log_gateway_event(
+        "gateway.invalid_body",
+        json!({
+            "path": req.path(),
+            "method": req.method().as_str(),
+            "reason": reason,
+        }),
+    );
+    let response = versioned_json(
+        HttpResponse::build(err.status_code()),
+        json!({ "error": message }),
+    );
+    InternalError::from_response(err, response).into()
+}
+
+/// Log-safe summary of user-supplied text: `<field>_length`, plus a stable
+/// `<field>_sha256` when `hash` is set.  Every log event that describes user
+/// content (queries, documents) must go through here so the raw text is never
+/// emitted.
+fn redacted_summary(field: &str, text: &str, hash: bool) -> serde_json::Map<String, Value> {
+    let mut summary = serde_json::Map::new();
+    summary.insert(format!("{}_length", field), json!(text.len()));
+    if hash {
+        summary.insert(
+            format!("{}_sha256", field),
+            json!(format!("{:x}", Sha256::digest(text.as_bytes()))),
+        );
     }
+    summary
 }
 
-/// Health check endpoint.
-async fn health_handler() -> impl Responder {
-    HttpResponse::Ok().body("OK")
+/// Redacted query summary, hashed when `GATEWAY_LOG_QUERY_HASH` is enabled.
+///
+/// The hash covers `normalize_query(query)` so trivially different spellings of
+/// the same question dedup together; the length is that of the raw query.
+fn redact_query(query: &str) -> serde_json::Map<String, Value> {
+    let mut summary = redacted_summary("query", query, false);
+    if env_flag("GATEWAY_LOG_QUERY_HASH") {
+        let normalized = normalize_query(query);
+        summary.insert(
+            "query_sha256".to_string(),
+            json!(format!("{:x}", Sha256::digest(normalized.as_bytes()))),
+        );
+    }
+    summary
 }
 
-/// Entry point.  Starts the Actix server and registers routes.
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let port = std::env::var("RUST_API_PORT")
-        .ok()
-        .and_then(|p| p.parse::<u16>().ok())
-        .unwrap_or(8000);
-    println!("Starting Rust API on port {}", port);
-    HttpServer::new(|| {
-        App::new()
-            .app_data(web::Data::new(Client::new()))
-            .route("/api/ask", web::post().to(ask_handler))
-            .route("/api/add_doc", web::post().to(add_doc_handler))
-            .route("/api/health", web::get().to(health_handler))
+/// Canonical form of a query used for cache keys and log hashes (never for the
+/// text forwarded to Python): trimmed, with every run of Unicode whitespace
+/// collapsed to a single ASCII space, and lowercased (Unicode-aware) when
+/// `GATEWAY_QUERY_IGNORE_CASE` is enabled.  Characters are otherwise left
+/// as-is, so e.g. full-width and ASCII letters stay distinct.
+fn normalize_query(query: &str) -> String {
+    let collapsed = query.split_whitespace().collect::<Vec<_>>().join(" ");
+    if env_flag("GATEWAY_QUERY_IGNORE_CASE") {
+        collapsed.to_lowercase()
+    } else {
+        collapsed
+    }
+}
+
+/// Whether an `Accept` header admits at least one supported media type.
+///
+/// A missing or empty header (or a wildcard) is treated as acceptable, and
+/// ranges with `q=0` are ignored.
+fn accepts_supported_type(accept: Option<&str>) -> bool {
+    let Some(accept) = accept.filter(|accept| !accept.trim().is_empty()) else {
+        return true;
+    };
+    accept.split(',').any(|range| {
+        let mut parts = range.split(';');
+        let media_range = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let rejected = parts.any(|param| {
+            param
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        !rejected
+            && SUPPORTED_MEDIA_TYPES.iter().any(|supported| {
+                let (kind, _) = supported.split_once('/').unwrap_or((supported, ""));
+                media_range == "*/*"
+                    || media_range == *supported
+                    || media_range == format!("{}/*", kind)
+            })
     })
-    .bind(("127.0.0.1", port))?
-    .run()
-    .await
+}
+
+/// Middleware returning `406 Not Acceptable` when the client's `Accept`
+/// header excludes every media type the gateway can produce.
+async fn negotiate_accept(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .map(|value| value.to_str().unwrap_or(""));
+    if accepts_supported_type(accept) {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+    log_gateway_event(
+        "gateway.not_acceptable",
+        json!({
+            "path": req.path(),
+            "method": req.method().as_str(),
+        }),
+    );
+    let response = versioned_json(
+        HttpResponse::NotAcceptable(),
+        json!({
+            "error": "None of the requested media types are supported",
+            "supported": SUPPORTED_MEDIA_TYPES,
+        }),
+    );
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+/// Whether a `Content-Type` header value is JSON, ignoring parameters such as
+/// `; charset=utf-8` and letter case.
+fn is_json_content_type(content_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// Middleware returning `415 Unsupported Media Type` for POST requests whose
+/// `Content-Type` is missing or not `application/json`, before any body parsing.
+async fn require_json_content_type(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if req.method() != actix_web::http::Method::POST
+        || content_type.is_some_and(is_json_content_type)
+    {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+    log_gateway_event(
+        "gateway.unsupported_media_type",
+        json!({
+            "path": req.path(),
+            "method": req.method().as_str(),
+            "content_type": content_type,
+        }),
+    );
+    let response = versioned_json(
+        HttpResponse::UnsupportedMediaType(),
+        json!({
+            "error": "POST bodies must be sent with Content-Type: application/json",
+            "supported": ["application/json"],
+        }),
+    );
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+/// HMAC-SHA256 (RFC 2104) of `message` under `key`, hex-encoded.
+fn hmac_sha256_hex(key: &[u8], message: &[u8]) -> String {
+    const BLOCK_LEN: usize = 64;
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    let outer = Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize();
+    format!("{:x}", outer)
+}
+
+/// Middleware signing successful responses when `GATEWAY_SIGNING_SECRET` is
+/// set: `X-Signature` carries the hex HMAC-SHA256 of the exact body bytes under
+/// that secret, computed before any `Content-Encoding` is applied.  Streamed
+/// bodies are left unsigned.
+async fn sign_responses(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let res = next.call(req).await?;
+    let Some(secret) = std::env::var("GATEWAY_SIGNING_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+    else {
+        return Ok(res.map_into_left_body());
+    };
+    if !res.status().is_success() {
+        return Ok(res.map_into_left_body());
+    }
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    match body.try_into_bytes() {
+        Ok(bytes) => {
+            let signature = hmac_sha256_hex(secret.as_bytes(), &bytes);
+            let mut res = res.set_body(bytes);
+            if let Ok(value) = header::HeaderValue::from_str(&signature) {
+                res.headers_mut()
+                    .insert(header::HeaderName::from_static(SIGNATURE_HEADER), value);
+            }
+            Ok(ServiceResponse::new(req, res).map_into_right_body())
+        }
+        Err(body) => Ok(ServiceResponse::new(req, res.set_body(body)).map_into_left_body()),
+    }
+}
+
+/// Duplicate of a client's TCP socket, stored as connection data by
+/// `HttpServer::on_connect` so handlers can notice a client that hung up.
+/// Only plain-HTTP connections on Unix get one; TLS streams are not captured.
+#[derive(Clone)]
+struct ClientSocket(Arc<std::net::TcpStream>);
+
+impl ClientSocket {
+    /// Whether the peer has closed the connection.  The socket is non-blocking,
+    /// so a peek returns `WouldBlock` while the client is connected but idle and
+    /// zero bytes once it has gone away.
+    fn closed(&self) -> bool {
+        let mut byte = [0u8; 1];
+        match self.0.peek(&mut byte) {
+            Ok(read) => read == 0,
+            Err(err) => !matches!(
+                err.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+            ),
+        }
+    }
+}
+
+/// `on_connect` hook storing a `ClientSocket` for each plain TCP connection.
+fn capture_client_socket(conn: &dyn std::any::Any, data: &mut Extensions) {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+        if let Some(stream) = conn.downcast_ref::<actix_web::rt::net::TcpStream>() {
+            if let Ok(fd) = stream.as_fd().try_clone_to_owned() {
+                data.insert(ClientSocket(Arc::new(std::net::TcpStream::from(fd))));
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (conn, data);
+}
+
+/// Resolves once the client behind `req` disconnects (checked every
+/// `DISCONNECT_POLL_MS`), or never when its socket was not captured.
+async fn client_disconnected(req: &HttpRequest) {
+    let Some(socket) = req.conn_data::<ClientSocket>().cloned() else {
+        return std::future::pending().await;
+    };
+    while !socket.closed() {
+        sleep(Duration::from_millis(DISCONNECT_POLL_MS)).await;
+    }
+}
+
+/// Process-wide request counters shared by the counting middleware and
+/// `/api/status`.
+struct RequestCounters {
+    started_at: Instant,
+    total: AtomicU64,
+    in_flight: AtomicU64,
+}
+
+impl RequestCounters {
+    fn new() -> Self {
+        RequestCounters {
+            started_at: Instant::now(),
+            total: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Decrements the in-flight count when a request finishes, including when the
+/// client disconnects and the handler future is dropped.
+struct InFlightGuard<'a>(&'a AtomicU64);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Middleware counting every request served and the number currently in flight.
+async fn count_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(counters) = req.app_data::<web::Data<RequestCounters>>().cloned() else {
+        return next.call(req).await;
+    };
+    counters.total.fetch_add(1, Ordering::Relaxed);
+    counters.in_flight.fetch_add(1, Ordering::Relaxed);
+    let _guard = InFlightGuard(&counters.in_flight);
+    next.call(req).await
+}
+
+/// Converts `web::Query` extraction failures into the standard `{"error": ...}` shape.
+fn query_error_handler(err: QueryPayloadError, req: &HttpRequest) -> actix_web::Error {
+    log_gateway_event(
+        "gateway.invalid_query_string",
+        json!({
+            "path": req.path(),
+            "method": req.method().as_str(),
+        }),
+    );
+    let response = versioned_json(
+        HttpResponse::BadRequest(),
+        json!({ "error": format!("Invalid query string: {}", err) }),
+    );
+    InternalError::from_response(err, response).into()
+}
+
+/// Pretty-print structured gateway logs.
+///
+/// Callers must not place user-supplied text in `details`; summarize it with
+/// `redacted_summary`/`redact_query` instead.
+fn log_gateway_event(event: &str, details: Value) {
+    let seq = LOG_SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1;
+    let log_entry = json!({
+        "seq": seq,
+        "event": event,
+        "details": details,
+    });
+    #[cfg(test)]
+    tests::capture_log(&log_entry);
+    match serde_json::to_string_pretty(&log_entry) {
+        Ok(pretty) => println!("{}", pretty),
+        Err(_) => println!("{}", log_entry),
+    }
+}
+
+/// Helper to compute the python service base urls from the environment.
+///
+/// `PYTHON_AI_URL` may hold a comma-separated list of replicas.
+fn python_service_base_urls() -> Vec<String> {
+    let urls: Vec<String> = std::env::var("PYTHON_AI_URL")
+        .unwrap_or_default()
+        .split(',')
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+        .collect();
+    if urls.is_empty() {
+        vec![PYTHON_DEFAULT_URL.to_string()]
+    } else {
+        urls
+    }
+}
+
+/// Round-robin cursor shared by all workers for picking a Python backend.
+static BACKEND_CURSOR: AtomicUsize = AtomicUsize::new(0);
+
+/// Latest `X-Backend-Queue-Depth` reported by any backend, with when it arrived.
+static BACKEND_QUEUE_DEPTH: Mutex<Option<(u64, Instant)>> = Mutex::new(None);
+
+/// Remembers the queue depth a backend reported on one of its responses.
+fn record_backend_queue_depth(headers: &reqwest::header::HeaderMap) {
+    let Some(depth) = headers
+        .get(BACKEND_QUEUE_DEPTH_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+    else {
+        return;
+    };
+    *BACKEND_QUEUE_DEPTH.lock().unwrap() = Some((depth, Instant::now()));
+}
+
+/// Sheds an ask with `503` while the backend's last reported queue depth is above
+/// `BACKEND_QUEUE_SHED_THRESHOLD`.  Readings older than
+/// `BACKEND_QUEUE_DEPTH_TTL_MS` are ignored, so once the backend stops being
+/// asked a request gets through and refreshes the reading.
+fn shed_on_backend_queue_depth(req: &HttpRequest) -> Result<(), HttpResponse> {
+    let Some(threshold) = env_parse::<u64>("BACKEND_QUEUE_SHED_THRESHOLD") else {
+        return Ok(());
+    };
+    let Some((depth, reported_at)) = *BACKEND_QUEUE_DEPTH.lock().unwrap() else {
+        return Ok(());
+    };
+    if depth <= threshold
+        || reported_at.elapsed() >= Duration::from_millis(BACKEND_QUEUE_DEPTH_TTL_MS)
+    {
+        return Ok(());
+    }
+    log_gateway_event(
+        "gateway.load_shed",
+        json!({
+            "path": req.path(),
+            "method": req.method().as_str(),
+            "queue_depth": depth,
+            "threshold": threshold,
+        }),
+    );
+    let mut response = versioned_json(
+        HttpResponse::ServiceUnavailable(),
+        json!({
+            "error": "Backend is overloaded; retry shortly",
+            "queue_depth": depth,
+        }),
+    );
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, header::HeaderValue::from(1));
+    Err(response)
+}
+
+/// Resolves a Python endpoint path, allowing an environment override.
+fn python_endpoint_path(env_var: &str, default: &str) -> String {
+    std::env::var(env_var).unwrap_or_else(|_| default.to_string())
+}
+
+/// Reads and parses an optional environment variable, ignoring unparsable values.
+fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse::<T>().ok())
+}
+
+/// Reads a boolean flag from the environment (`1`, `true`, `yes` or `on`).
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|value| {
+            matches!(
+                value.trim().to_ascii_lowercase().as_str(),
+                "1" | "true" | "yes" | "on"
+            )
+        })
+        .unwrap_or(false)
+}
+
+/// Answer previously returned by the Python backend for a given query.
+struct CachedAnswer {
+    response: PythonAskResponse,
+    stored_at: Instant,
+}
+
+/// In-memory TTL cache of `/api/ask` answers keyed by the forwarded payload.
+///
+/// Disabled unless `GATEWAY_ASK_CACHE_TTL_MS` is set to a positive value.
+///
+/// Refreshes are single-flight: a miss goes through `AskCoalescer` under the
+/// same key, so when an entry expires one request calls Python and concurrent
+/// requests share its outcome (errors included) instead of stampeding the
+/// backend.
+struct AskCache {
+    ttl: Option<Duration>,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, CachedAnswer>>,
+}
+
+/// One upstream ask shared by concurrent identical requests.  Failures keep the
+/// gateway error's status and JSON body so every caller can rebuild it.
+type AskFlight = AsyncOnceCell<Result<Upstream<PythonAskResponse>, (StatusCode, web::Bytes)>>;
+
+/// Collapses concurrent identical asks (same normalized payload, keyed like the
+/// ask cache) into a single call to Python whose outcome every caller shares.
+/// Unlike `AskCache` nothing outlives the call: entries are removed as soon as
+/// they resolve, so this works with the cache disabled or bypassed too.
+struct AskCoalescer {
+    in_flight: Mutex<HashMap<String, Arc<AskFlight>>>,
+}
+
+impl AskCoalescer {
+    fn new() -> Self {
+        AskCoalescer {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Locks the in-flight map, recovering from a poisoned lock: it only holds
+    /// shared handles, so nothing can have been left half-written.
+    fn lock_in_flight(&self) -> MutexGuard<'_, HashMap<String, Arc<AskFlight>>> {
+        self.in_flight.lock().unwrap_or_else(|poisoned| {
+            self.in_flight.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    /// Joins the in-flight call for `key`, registering a new one if none exists.
+    fn join(&self, key: &str) -> Arc<AskFlight> {
+        self.lock_in_flight()
+            .entry(key.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// Forgets a resolved call, unless a newer one has already replaced it.
+    fn finish(&self, key: &str, flight: &Arc<AskFlight>) {
+        let mut in_flight = self.lock_in_flight();
+        if in_flight
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, flight))
+        {
+            in_flight.remove(key);
+        }
+    }
+
+    /// Forgets a call abandoned before it resolved, unless other requests are
+    /// still waiting on it (one of them will take the call over).
+    fn abandon(&self, key: &str, flight: &Arc<AskFlight>) {
+        let mut in_flight = self.lock_in_flight();
+        if in_flight
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, flight))
+            && Arc::strong_count(flight) == 2
+        {
+            in_flight.remove(key);
+        }
+    }
+}
+
+impl AskCache {
+    fn from_env() -> Self {
+        let ttl = env_parse::<u64>("GATEWAY_ASK_CACHE_TTL_MS")
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis);
+        let max_entries = env_parse::<usize>("GATEWAY_ASK_CACHE_MAX_ENTRIES")
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_ASK_CACHE_MAX_ENTRIES);
+        AskCache {
+            ttl,
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.ttl.is_some()
+    }
+
+    /// Locks the entries, recovering from a poisoned lock instead of panicking.
+    ///
+    /// A panic while the lock was held may have left entries half-written, so
+    /// the cache is emptied and the current access proceeds as a miss.
+    fn lock_entries(&self) -> MutexGuard<'_, HashMap<String, CachedAnswer>> {
+        self.entries.lock().unwrap_or_else(|poisoned| {
+            let mut entries = poisoned.into_inner();
+            log_gateway_event(
+                "gateway.cache_lock_recovered",
+                json!({ "discarded_entries": entries.len() }),
+            );
+            entries.clear();
+            self.entries.clear_poison();
+            entries
+        })
+    }
+
+    /// Returns a fresh cached answer, if any.
+    fn get(&self, key: &str) -> Option<PythonAskResponse> {
+        let ttl = self.ttl?;
+        let entries = self.lock_entries();
+        entries
+            .get(key)
+            .filter(|entry| entry.stored_at.elapsed() < ttl)
+            .map(|entry| entry.response.clone())
+    }
+
+    /// Stores (or refreshes) the answer for a query, evicting stale entries when full.
+    fn insert(&self, key: &str, response: &PythonAskResponse) {
+        let Some(ttl) = self.ttl else {
+            return;
+        };
+        let mut entries = self.lock_entries();
+        if !entries.contains_key(key) && entries.len() >= self.max_entries {
+            entries.retain(|_, entry| entry.stored_at.elapsed() < ttl);
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.stored_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(
+            key.to_owned(),
+            CachedAnswer {
+                response: response.clone(),
+                stored_at: Instant::now(),
+            },
+        );
+    }
+}
+
+/// One ingestion behind an `Idempotency-Key`, shared by every request using the key.
+type IngestFlight = AsyncOnceCell<Upstream<PythonAddDocResponse>>;
+
+/// What an `Idempotency-Key` was first used for.
+struct IdempotencyEntry {
+    /// SHA-256 of the canonical JSON request body.
+    request_sha256: String,
+    flight: Arc<IngestFlight>,
+    created_at: Instant,
+}
+
+/// Remembers the ingestion started for an `Idempotency-Key` so retried
+/// requests are answered without re-forwarding to Python.  Keys are reserved
+/// before the document is forwarded, so a duplicate arriving while the first
+/// request is still in flight waits for its `document_id` instead of ingesting
+/// the document again.
+struct IdempotencyStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<(String, String), IdempotencyEntry>>,
+}
+
+impl IdempotencyStore {
+    fn from_env() -> Self {
+        let ttl_ms =
+            env_parse::<u64>("GATEWAY_IDEMPOTENCY_TTL_MS").unwrap_or(DEFAULT_IDEMPOTENCY_TTL_MS);
+        IdempotencyStore {
+            ttl: Duration::from_millis(ttl_ms),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserves a key for a request body, returning the ingestion to join: a
+    /// new one, or the one already started (or finished) for the same body.
+    /// `None` means the key was first used with a different body.  Expired
+    /// entries nobody is waiting on are dropped along the way.
+    fn reserve(
+        &self,
+        api_key: &str,
+        idempotency_key: &str,
+        request_sha256: &str,
+    ) -> Option<Arc<IngestFlight>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| {
+            entry.created_at.elapsed() < self.ttl || Arc::strong_count(&entry.flight) > 1
+        });
+        let entry = entries
+            .entry((api_key.to_owned(), idempotency_key.to_owned()))
+            .or_insert_with(|| IdempotencyEntry {
+                request_sha256: request_sha256.to_owned(),
+                flight: Arc::default(),
+                created_at: Instant::now(),
+            });
+        (entry.request_sha256 == request_sha256).then(|| entry.flight.clone())
+    }
+
+    /// Forgets a key whose ingestion failed so the client can retry it, unless
+    /// other requests are still waiting on it (one of them will retry instead).
+    fn abandon(&self, api_key: &str, idempotency_key: &str, flight: &Arc<IngestFlight>) {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (api_key.to_owned(), idempotency_key.to_owned());
+        if entries.get(&key).is_some_and(|entry| {
+            Arc::ptr_eq(&entry.flight, flight) && Arc::strong_count(flight) == 2
+        }) {
+            entries.remove(&key);
+        }
+    }
+}
+
+/// SHA-256 of a request body's canonical JSON (object keys sorted), so equal
+/// documents hash equally whatever order their metadata arrived in.
+fn request_sha256<T: Serialize>(body: &T) -> String {
+    let canonical = serde_json::to_value(body)
+        .unwrap_or(Value::Null)
+        .to_string();
+    format!("{:x}", Sha256::digest(canonical.as_bytes()))
+}
+
+/// Reads a non-empty `Idempotency-Key` header, if supplied.
+fn idempotency_key(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+/// Short, non-reversible identifier for an API key, safe to put in logs.
+fn key_hash(api_key: &str) -> String {
+    format!("{:x}", Sha256::digest(api_key.as_bytes()))[..16].to_string()
+}
+
+/// Who sent a request, for access logs: the client IP and `User-Agent`.
+struct Caller {
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Caller {
+    /// Uses the TCP peer address unless `GATEWAY_TRUST_PROXY` names how many
+    /// reverse proxies sit in front of the gateway (`true` means one).  Each
+    /// proxy appends the address it received the request from to
+    /// `X-Forwarded-For`, so the client is the entry that many hops from the
+    /// right; entries further left are client-supplied and may be spoofed.
+    fn from_request(req: &HttpRequest) -> Self {
+        let trusted_hops = env_parse::<usize>("GATEWAY_TRUST_PROXY")
+            .or_else(|| env_flag("GATEWAY_TRUST_PROXY").then_some(1))
+            .filter(|hops| *hops > 0);
+        let forwarded = trusted_hops.and_then(|hops| {
+            let chain: Vec<&str> = req
+                .headers()
+                .get_all(header::X_FORWARDED_FOR)
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .collect();
+            chain
+                .get(chain.len().saturating_sub(hops))
+                .map(|entry| scrub_log_snippet(entry, MAX_CLIENT_IP_LEN))
+        });
+        Caller {
+            ip: forwarded.or_else(|| req.peer_addr().map(|addr| addr.ip().to_string())),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|agent| scrub_log_snippet(agent, MAX_USER_AGENT_LOG_LEN)),
+        }
+    }
+}
+
+/// Token bucket state for one API key, or for the global throttle.
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Per-API-key token-bucket rate limiter.
+///
+/// Disabled unless `GATEWAY_RATE_LIMIT_RPS` is set; `GATEWAY_RATE_LIMIT_BURST`
+/// sets the bucket size (defaults to the per-second rate, minimum one).
+struct RateLimiter {
+    rps: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    fn from_env() -> Option<Self> {
+        let rps = env_parse::<f64>("GATEWAY_RATE_LIMIT_RPS").filter(|rps| *rps > 0.0)?;
+        let burst = env_parse::<f64>("GATEWAY_RATE_LIMIT_BURST")
+            .filter(|burst| *burst >= 1.0)
+            .unwrap_or(rps.max(1.0));
+        Some(RateLimiter {
+            rps,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes one token for the key, or returns how long until one is available.
+    fn try_acquire(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        let refill = now.duration_since(bucket.updated_at).as_secs_f64() * self.rps;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rps))
+        }
+    }
+
+    /// Drops buckets that have been idle long enough to refill completely;
+    /// they are indistinguishable from a fresh bucket.
+    fn evict_idle(&self) {
+        let full_after = Duration::from_secs_f64(self.burst / self.rps);
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| bucket.updated_at.elapsed() < full_after);
+    }
+}
+
+/// Applies the per-key rate limit, producing a `429` with `Retry-After` when exceeded.
+fn enforce_rate_limit(
+    req: &HttpRequest,
+    limiter: &Option<RateLimiter>,
+    api_key: &str,
+) -> Result<(), HttpResponse> {
+    let Some(limiter) = limiter else {
+        return Ok(());
+    };
+    let key_hash = key_hash(api_key);
+    limiter.try_acquire(&key_hash).map_err(|wait| {
+        let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+        log_gateway_event(
+            "gateway.rate_limited",
+            json!({
+                "path": req.path(),
+                "method": req.method().as_str(),
+                "key_hash": key_hash,
+                "retry_after_secs": retry_after,
+            }),
+        );
+        let mut response = versioned_json(
+            HttpResponse::TooManyRequests(),
+            json!({ "error": "Rate limit exceeded", "retry_after_secs": retry_after }),
+        );
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+        response
+    })
+}
+
+/// Gateway-wide token bucket capping forwarded asks and document additions at
+/// `GATEWAY_GLOBAL_RPS` regardless of API key, with `GATEWAY_GLOBAL_BURST`
+/// tokens of headroom (defaults to the per-second rate, minimum one).  A single
+/// instance is shared by every worker; disabled when the rate is unset.
+struct GlobalThrottle {
+    rps: Option<f64>,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+    throttled: AtomicU64,
+}
+
+impl GlobalThrottle {
+    fn from_env() -> Self {
+        let rps = env_parse::<f64>("GATEWAY_GLOBAL_RPS").filter(|rps| *rps > 0.0);
+        let burst = env_parse::<f64>("GATEWAY_GLOBAL_BURST")
+            .filter(|burst| *burst >= 1.0)
+            .unwrap_or(rps.unwrap_or(1.0).max(1.0));
+        GlobalThrottle {
+            rps,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                updated_at: Instant::now(),
+            }),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes one token from the global budget, producing a `429` with
+    /// `Retry-After` when it is exhausted.
+    fn enforce(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(rps) = self.rps else {
+            return Ok(());
+        };
+        let wait = {
+            let now = Instant::now();
+            let mut bucket = self.bucket.lock().unwrap();
+            let refill = now.duration_since(bucket.updated_at).as_secs_f64() * rps;
+            bucket.tokens = (bucket.tokens + refill).min(self.burst);
+            bucket.updated_at = now;
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                return Ok(());
+            }
+            Duration::from_secs_f64((1.0 - bucket.tokens) / rps)
+        };
+        let throttled_total = self.throttled.fetch_add(1, Ordering::Relaxed) + 1;
+        let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+        log_gateway_event(
+            "gateway.global_throttled",
+            json!({
+                "path": req.path(),
+                "method": req.method().as_str(),
+                "global_rps": rps,
+                "retry_after_secs": retry_after,
+                "throttled_total": throttled_total,
+            }),
+        );
+        let mut response = versioned_json(
+            HttpResponse::TooManyRequests(),
+            json!({
+                "error": "Gateway is at its global request limit",
+                "retry_after_secs": retry_after,
+            }),
+        );
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+        Err(response)
+    }
+}
+
+/// Per-app request counters keyed on `X-App-ID`.
+///
+/// Cardinality is bounded: once `GATEWAY_MAX_TRACKED_APPS` distinct apps have
+/// been seen, further apps are counted in a shared overflow bucket.
+struct AppStats {
+    max_apps: usize,
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl AppStats {
+    fn from_env() -> Self {
+        AppStats {
+            max_apps: env_parse("GATEWAY_MAX_TRACKED_APPS").unwrap_or(DEFAULT_MAX_TRACKED_APPS),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record(&self, app_id: Option<&str>) {
+        let Some(app_id) = app_id else {
+            return;
+        };
+        let mut counts = self.counts.lock().unwrap();
+        let tracked = counts.len() - usize::from(counts.contains_key(APP_OVERFLOW_BUCKET));
+        let bucket = if counts.contains_key(app_id) || tracked < self.max_apps {
+            app_id
+        } else {
+            APP_OVERFLOW_BUCKET
+        };
+        *counts.entry(bucket.to_owned()).or_insert(0) += 1;
+    }
+
+    fn snapshot(&self) -> HashMap<String, u64> {
+        self.counts.lock().unwrap().clone()
+    }
+}
+
+/// Bounds concurrent upstream calls to `GATEWAY_MAX_CONCURRENT_UPSTREAM` and
+/// records how long requests queue for a permit, separating gateway queuing
+/// from backend slowness.  Unlimited (and unmeasured) when the variable is unset.
+struct UpstreamLimiter {
+    permits: Option<Semaphore>,
+    queue_waits: Mutex<VecDeque<u64>>,
+}
+
+impl UpstreamLimiter {
+    fn from_env() -> Self {
+        UpstreamLimiter {
+            permits: env_parse::<usize>("GATEWAY_MAX_CONCURRENT_UPSTREAM")
+                .filter(|limit| *limit > 0)
+                .map(Semaphore::new),
+            queue_waits: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Waits for an upstream permit, returning it along with the queue time in
+    /// milliseconds (both `None` when limiting is disabled).  The queue time
+    /// runs from `received`, when the handler started, so waits before the
+    /// permit call (throttling, a coalesced ask) count too.
+    async fn acquire(&self, received: Instant) -> (Option<SemaphorePermit<'_>>, Option<u64>) {
+        let Some(permits) = &self.permits else {
+            return (None, None);
+        };
+        // The semaphore is never closed, so acquiring cannot fail.
+        let permit = permits.acquire().await.ok();
+        let wait_ms = received.elapsed().as_millis() as u64;
+        let mut queue_waits = self.queue_waits.lock().unwrap();
+        if queue_waits.len() == QUEUE_WAIT_SAMPLES {
+            queue_waits.pop_front();
+        }
+        queue_waits.push_back(wait_ms);
+        (permit, Some(wait_ms))
+    }
+
+    /// p50/p90/p99 queue wait over the most recent `QUEUE_WAIT_SAMPLES` requests.
+    fn queue_wait_percentiles(&self) -> Option<Value> {
+        self.permits.as_ref()?;
+        let mut samples: Vec<u64> = self.queue_waits.lock().unwrap().iter().copied().collect();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        // Nearest-rank percentile.
+        let percentile = |p: usize| samples[(samples.len() * p).div_ceil(100).max(1) - 1];
+        Some(json!({
+            "p50": percentile(50),
+            "p90": percentile(90),
+            "p99": percentile(99),
+            "samples": samples.len(),
+        }))
+    }
+}
+
+/// Reads the optional `X-App-ID` header used to attribute requests to one of a
+/// tenant's apps.  Values must be at most `MAX_APP_ID_LEN` characters of
+/// `[A-Za-z0-9._-]`.
+fn extract_app_id(req: &HttpRequest) -> Result<Option<String>, HttpResponse> {
+    let Some(value) = req.headers().get(APP_ID_HEADER) else {
+        return Ok(None);
+    };
+    let app_id = value.to_str().unwrap_or("").trim();
+    let valid = !app_id.is_empty()
+        && app_id.len() <= MAX_APP_ID_LEN
+        && app_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if valid {
+        Ok(Some(app_id.to_owned()))
+    } else {
+        Err(reject_invalid(
+            req,
+            APP_ID_HEADER,
+            "format",
+            format!(
+                "{} must be 1-{} characters of letters, digits, '.', '_' or '-'",
+                APP_ID_HEADER, MAX_APP_ID_LEN
+            ),
+        ))
+    }
+}
+
+/// Limits applied to client-supplied metadata before it is forwarded to Python.
+struct MetadataLimits {
+    max_keys: usize,
+    max_key_len: usize,
+    max_value_len: usize,
+}
+
+impl MetadataLimits {
+    fn from_env() -> Self {
+        MetadataLimits {
+            max_keys: env_parse("GATEWAY_MAX_METADATA_KEYS").unwrap_or(DEFAULT_MAX_METADATA_KEYS),
+            max_key_len: env_parse("GATEWAY_MAX_METADATA_KEY_LEN")
+                .unwrap_or(DEFAULT_MAX_METADATA_KEY_LEN),
+            max_value_len: env_parse("GATEWAY_MAX_METADATA_VALUE_LEN")
+                .unwrap_or(DEFAULT_MAX_METADATA_VALUE_LEN),
+        }
+    }
+
+    /// Checks a metadata-style map (named `field` in messages), returning the
+    /// violated constraint and a message on failure.
+    fn validate(
+        &self,
+        field: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<(), (&'static str, String)> {
+        if metadata.len() > self.max_keys {
+            return Err((
+                "max_keys",
+                format!(
+                    "{} has {} keys; at most {} are allowed",
+                    field,
+                    metadata.len(),
+                    self.max_keys
+                ),
+            ));
+        }
+        for (key, value) in metadata {
+            if key.chars().count() > self.max_key_len {
+                return Err((
+                    "max_key_len",
+                    format!("{} key exceeds {} characters", field, self.max_key_len),
+                ));
+            }
+            if value.chars().count() > self.max_value_len {
+                return Err((
+                    "max_value_len",
+                    format!(
+                        "{} value for key '{}' exceeds {} characters",
+                        field, key, self.max_value_len
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Validates an optional metadata-style map (document `metadata`, ask `filter`),
+/// producing a `400` naming the field and the failed constraint.
+fn check_metadata(
+    req: &HttpRequest,
+    field: &str,
+    metadata: Option<&HashMap<String, String>>,
+    limits: &MetadataLimits,
+) -> Result<(), HttpResponse> {
+    let Some(metadata) = metadata else {
+        return Ok(());
+    };
+    limits
+        .validate(field, metadata)
+        .map_err(|(constraint, message)| reject_invalid(req, field, constraint, message))
+}
+
+/// Logs a `gateway.validation_failed` event and builds the matching `400`.
+fn reject_invalid(
+    req: &HttpRequest,
+    field: &str,
+    constraint: &str,
+    message: String,
+) -> HttpResponse {
+    log_gateway_event(
+        "gateway.validation_failed",
+        json!({
+            "path": req.path(),
+            "method": req.method().as_str(),
+            "field": field,
+            "constraint": constraint,
+        }),
+    );
+    versioned_json(
+        HttpResponse::BadRequest(),
+        json!({
+            "error": message,
+            "field": field,
+            "constraint": constraint,
+        }),
+    )
+}
+
+/// Validates an optional `session_id`: it must be non-empty and at most
+/// `MAX_SESSION_ID_LEN` characters.
+fn check_session_id(req: &HttpRequest, session_id: Option<&str>) -> Result<(), HttpResponse> {
+    match session_id {
+        Some(id) if id.trim().is_empty() => Err(reject_invalid(
+            req,
+            "session_id",
+            "non_empty",
+            "session_id must not be empty".to_string(),
+        )),
+        Some(id) if id.chars().count() > MAX_SESSION_ID_LEN => Err(reject_invalid(
+            req,
+            "session_id",
+            "max_len",
+            format!("session_id exceeds {} characters", MAX_SESSION_ID_LEN),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Validates a feedback submission: `request_id` must be an id returned by
+/// `/api/ask` (which also keeps free text out of the logged value), `comment`
+/// at most `MAX_FEEDBACK_COMMENT_LEN` characters.
+fn check_feedback(req: &HttpRequest, feedback: &FeedbackRequest) -> Result<(), HttpResponse> {
+    if !is_request_id(&feedback.request_id) {
+        return Err(reject_invalid(
+            req,
+            "request_id",
+            "format",
+            format!(
+                "request_id must be the {}-character hex id returned by /api/ask",
+                REQUEST_ID_LEN
+            ),
+        ));
+    }
+    if let Some(comment) = &feedback.comment {
+        if comment.chars().count() > MAX_FEEDBACK_COMMENT_LEN {
+            return Err(reject_invalid(
+                req,
+                "comment",
+                "max_len",
+                format!("comment exceeds {} characters", MAX_FEEDBACK_COMMENT_LEN),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Validates an embed request: between one and `MAX_EMBED_TEXTS` texts, each at
+/// most `MAX_EMBED_TEXT_LEN` characters.
+fn check_embed_texts(req: &HttpRequest, texts: &[String]) -> Result<(), HttpResponse> {
+    if texts.is_empty() {
+        return Err(reject_invalid(
+            req,
+            "texts",
+            "non_empty",
+            "texts must contain at least one entry".to_string(),
+        ));
+    }
+    if texts.len() > MAX_EMBED_TEXTS {
+        return Err(reject_invalid(
+            req,
+            "texts",
+            "max_items",
+            format!(
+                "texts has {} entries (max {})",
+                texts.len(),
+                MAX_EMBED_TEXTS
+            ),
+        ));
+    }
+    if let Some(index) = texts
+        .iter()
+        .position(|text| text.chars().count() > MAX_EMBED_TEXT_LEN)
+    {
+        return Err(reject_invalid(
+            req,
+            "texts",
+            "max_len",
+            format!("texts[{}] exceeds {} characters", index, MAX_EMBED_TEXT_LEN),
+        ));
+    }
+    Ok(())
+}
+
+/// Answer length limit forwarded to Python so it generates within bounds: the
+/// request's `max_answer_chars`, else `GATEWAY_DEFAULT_MAX_ANSWER_CHARS`, clamped
+/// to `GATEWAY_MAX_ANSWER_CHARS_CAP`.  `None` leaves the length to the backend.
+fn answer_length_limit(requested: Option<u32>) -> Option<u32> {
+    let cap = env_parse::<u32>("GATEWAY_MAX_ANSWER_CHARS_CAP")
+        .filter(|cap| *cap > 0)
+        .unwrap_or(DEFAULT_MAX_ANSWER_CHARS_CAP);
+    requested
+        .or_else(|| env_parse("GATEWAY_DEFAULT_MAX_ANSWER_CHARS"))
+        .map(|limit: u32| limit.clamp(1, cap))
+}
+
+/// Cache key for an ask: the serialized payload forwarded to Python with its
+/// query replaced by `normalize_query`, so requests differing in any forwarded
+/// field never share an answer but trivially different queries do.
+fn ask_cache_key(payload: &Value) -> String {
+    let mut key = payload.clone();
+    if let Some(query) = payload["query"].as_str() {
+        key["query"] = json!(normalize_query(query));
+    }
+    key.to_string()
+}
+
+/// Default and maximum `top_k` for asks, from `GATEWAY_DEFAULT_TOP_K` and
+/// `GATEWAY_MAX_TOP_K`.  Inconsistent values (zero, or a default above the
+/// maximum) are reported as a `gateway.config` warning and both fall back to
+/// the built-in `DEFAULT_TOP_K`/`MAX_TOP_K`.
+struct TopKLimits {
+    default: u8,
+    max: u8,
+}
+
+impl TopKLimits {
+    fn from_env() -> Self {
+        let default = env_parse::<u8>("GATEWAY_DEFAULT_TOP_K").unwrap_or(DEFAULT_TOP_K);
+        let max = env_parse::<u8>("GATEWAY_MAX_TOP_K").unwrap_or(MAX_TOP_K);
+        if default == 0 || default > max {
+            log_gateway_event(
+                "gateway.config",
+                json!({
+                    "setting": "GATEWAY_DEFAULT_TOP_K",
+                    "warning": format!(
+                        "default top_k {} must be between 1 and max top_k {}; using {} and {}",
+                        default, max, DEFAULT_TOP_K, MAX_TOP_K
+                    ),
+                }),
+            );
+            return TopKLimits {
+                default: DEFAULT_TOP_K,
+                max: MAX_TOP_K,
+            };
+        }
+        TopKLimits { default, max }
+    }
+
+    /// Applies the default to a missing `top_k` and clamps it to 1..=max.
+    fn resolve(&self, requested: Option<u8>) -> u8 {
+        requested.unwrap_or(self.default).clamp(1, self.max)
+    }
+}
+
+/// Per-API-key default request fields, loaded from `GATEWAY_KEY_PROFILES`
+/// (a JSON object mapping each key to an object of defaults, e.g.
+/// `{"tenant-key": {"model": "claude-haiku", "language": "de"}}`).
+struct KeyProfiles {
+    profiles: HashMap<String, serde_json::Map<String, Value>>,
+}
+
+impl KeyProfiles {
+    fn from_env() -> Self {
+        let profiles = match std::env::var("GATEWAY_KEY_PROFILES") {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|err| {
+                log_gateway_event(
+                    "gateway.config",
+                    json!({
+                        "setting": "GATEWAY_KEY_PROFILES",
+                        "warning": format!("ignoring invalid key profiles: {}", err),
+                    }),
+                );
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        KeyProfiles { profiles }
+    }
+
+    /// Fills the ask fields the client omitted with the key's profile
+    /// defaults, before validation so profile values are held to the same
+    /// limits as client ones.  Also returns the profile fields `AskRequest`
+    /// does not model, which are forwarded to Python as-is.  A profile whose
+    /// values do not fit the request shape is ignored entirely, with a
+    /// `gateway.config` warning.
+    fn complete_request(
+        &self,
+        api_key: &str,
+        req: AskRequest,
+    ) -> (AskRequest, serde_json::Map<String, Value>) {
+        let Some(defaults) = self.profiles.get(api_key) else {
+            return (req, serde_json::Map::new());
+        };
+        let completed = serde_json::to_value(&req).and_then(|mut value| {
+            let extras = defaults
+                .iter()
+                .filter(|(name, _)| value.get(name.as_str()).is_none())
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            self.apply_defaults(api_key, &mut value);
+            Ok((serde_json::from_value(value)?, extras))
+        });
+        completed.unwrap_or_else(|err| {
+            log_gateway_event(
+                "gateway.config",
+                json!({
+                    "setting": "GATEWAY_KEY_PROFILES",
+                    "warning": format!("ignoring key profile that does not fit an ask: {}", err),
+                }),
+            );
+            (req, serde_json::Map::new())
+        })
+    }
+
+    /// Fills fields missing from `payload` with the key's profile defaults;
+    /// values already present always win.
+    fn apply_defaults(&self, api_key: &str, payload: &mut Value) {
+        let (Some(defaults), Value::Object(fields)) = (self.profiles.get(api_key), payload) else {
+            return;
+        };
+        for (name, value) in defaults {
+            if fields.get(name).is_none_or(Value::is_null) {
+                fields.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Whether the client asked for a fresh answer via `Cache-Control: no-cache`
+/// or `X-Bypass-Cache: true`.
+fn cache_bypass_requested(req: &HttpRequest) -> bool {
+    let no_cache = req
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"))
+        })
+        .unwrap_or(false);
+    let bypass = req
+        .headers()
+        .get(BYPASS_CACHE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    no_cache || bypass
+}
+
+/// Reads an upstream error body and reduces it to a loggable snippet.
+///
+/// Backends often echo the request in their errors, so every string from the
+/// forwarded payload (`request_texts`) is first replaced with `[redacted]`.
+/// Control characters are then replaced with spaces and the result is
+/// truncated to `ERROR_BODY_LOG_CHARS` characters; `0` disables body capture
+/// entirely.
+async fn error_body_snippet(resp: reqwest::Response, request_texts: &[String]) -> Option<String> {
+    let max_chars =
+        env_parse::<usize>("ERROR_BODY_LOG_CHARS").unwrap_or(DEFAULT_ERROR_BODY_LOG_CHARS);
+    if max_chars == 0 {
+        return None;
+    }
+    let mut body = resp.text().await.ok()?;
+    for text in request_texts {
+        // Echoed either verbatim or JSON-escaped inside a string.
+        let escaped = serde_json::to_string(text).unwrap_or_default();
+        for echoed in [text.as_str(), escaped.trim_matches('"')] {
+            if !echoed.is_empty() {
+                body = body.replace(echoed, "[redacted]");
+            }
+        }
+    }
+    Some(scrub_log_snippet(&body, max_chars))
+}
+
+/// Every non-empty string in a JSON payload, longest first so that a string
+/// containing another is redacted whole.
+fn payload_strings(payload: &Value) -> Vec<String> {
+    fn collect(value: &Value, out: &mut Vec<String>) {
+        match value {
+            Value::String(text) if !text.is_empty() => out.push(text.clone()),
+            Value::Array(items) => items.iter().for_each(|item| collect(item, out)),
+            Value::Object(fields) => fields.values().for_each(|field| collect(field, out)),
+            _ => {}
+        }
+    }
+    let mut strings = Vec::new();
+    collect(payload, &mut strings);
+    strings.sort_by_key(|text| std::cmp::Reverse(text.len()));
+    strings
+}
+
+/// Strips control characters and truncates text destined for a log event.
+fn scrub_log_snippet(text: &str, max_chars: usize) -> String {
+    let mut snippet: String = text
+        .trim()
+        .chars()
+        .take(max_chars)
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    if text.trim().chars().count() > max_chars {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Returns the first `FAILOVER_ON_ERROR_PATTERNS` entry (comma-separated
+/// substrings) found in an upstream error body.
+fn matching_failover_pattern(body: &[u8]) -> Option<String> {
+    let patterns = std::env::var("FAILOVER_ON_ERROR_PATTERNS").ok()?;
+    let body = String::from_utf8_lossy(body);
+    patterns
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .find(|pattern| body.contains(pattern))
+        .map(str::to_owned)
+}
+
+/// Sends a JSON payload to the Python backend with retry and exponential backoff.
+async fn post_with_retry<T, U>(
+    client: &Client,
+    endpoint: &str,
+    payload: &T,
+    timeout: Option<Duration>,
+    started: Instant,
+) -> Result<Upstream<U>, HttpResponse>
+where
+    T: Serialize,
+    U: DeserializeOwned,
+{
+    request_with_retry(
+        client,
+        Method::POST,
+        endpoint,
+        &[],
+        Some(payload),
+        timeout,
+        started,
+    )
+    .await
+}
+
+/// Issues a body-less GET to the Python backend with retry and exponential
+/// backoff, sending `query` as the URL query string.
+async fn get_with_retry<U>(
+    client: &Client,
+    endpoint: &str,
+    query: &[(&str, String)],
+    timeout: Option<Duration>,
+    started: Instant,
+) -> Result<Upstream<U>, HttpResponse>
+where
+    U: DeserializeOwned,
+{
+    request_with_retry::<(), U>(client, Method::GET, endpoint, query, None, timeout, started).await
+}
+
+/// Capped exponential backoff before retrying after `attempt` (zero-based):
+/// `BASE_BACKOFF_MS * 2^attempt`, never more than `GATEWAY_MAX_BACKOFF_MS`
+/// (default 5000).  The retry deadline still applies on top: a backoff that
+/// would overrun `GATEWAY_RETRY_DEADLINE_MS` ends the retries instead.
+fn retry_backoff(attempt: usize) -> Duration {
+    let max_ms = env_parse::<u64>("GATEWAY_MAX_BACKOFF_MS").unwrap_or(DEFAULT_MAX_BACKOFF_MS);
+    let factor = u32::try_from(attempt)
+        .ok()
+        .and_then(|attempt| 1u64.checked_shl(attempt))
+        .unwrap_or(u64::MAX);
+    Duration::from_millis(BASE_BACKOFF_MS.saturating_mul(factor).min(max_ms))
+}
+
+/// Per-operation upstream timeout read from `env_var` in milliseconds (e.g.
+/// `PYTHON_ASK_TIMEOUT_MS`); unset or `0` leaves attempts unbounded.
+fn python_timeout(env_var: &str) -> Option<Duration> {
+    env_parse::<u64>(env_var)
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis)
+}
+
+/// Serializes an upstream JSON body, gzipping it when it is at least
+/// `GATEWAY_UPSTREAM_GZIP_MIN_BYTES` long (opt-in: not every backend accepts
+/// `Content-Encoding: gzip`).  Returns the bytes and whether they are compressed.
+fn encode_upstream_body<T: Serialize + ?Sized>(payload: &T) -> std::io::Result<(Vec<u8>, bool)> {
+    let json = serde_json::to_vec(payload)?;
+    match env_parse::<usize>("GATEWAY_UPSTREAM_GZIP_MIN_BYTES") {
+        Some(min_bytes) if json.len() >= min_bytes => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&json)?;
+            Ok((encoder.finish()?, true))
+        }
+        _ => Ok((json, false)),
+    }
+}
+
+/// Aggregate retry outcomes for one Python endpoint.
+#[derive(Clone, Copy, Default, Serialize)]
+struct RetryStats {
+    /// Retries attempted (attempts after the first).
+    retries: u64,
+    /// Calls that succeeded after at least one retry.
+    recovered: u64,
+    /// Calls that failed on every one of `MAX_RETRIES` attempts.
+    exhausted: u64,
+}
+
+/// Retry counters keyed by Python endpoint path, reported by `/api/status`.
+static RETRY_STATS: Mutex<BTreeMap<String, RetryStats>> = Mutex::new(BTreeMap::new());
+
+fn record_retry_stats(endpoint: &str, update: impl FnOnce(&mut RetryStats)) {
+    let mut stats = RETRY_STATS.lock().unwrap();
+    update(stats.entry(endpoint.to_owned()).or_default());
+}
+
+/// Why a single upstream attempt failed.  The variant alone decides whether
+/// `request_with_retry` tries again (see `retriable`).
+enum AttemptFailure {
+    /// Connect error or other transport failure before a response arrived.
+    Network(String),
+    /// The attempt exceeded its per-request timeout (or the remaining deadline).
+    Timeout(String),
+    /// The backend answered with a 5xx status.
+    ServerError {
+        status: u16,
+        body_snippet: Option<String>,
+    },
+    /// A 4xx body matched one of `FAILOVER_ON_ERROR_PATTERNS`.
+    FailoverPattern { status: u16, pattern: String },
+    /// Any other 4xx status: the request itself was rejected.  The upstream
+    /// error body is kept so it can be passed through to the client.
+    ClientError { status: u16, body: Value },
+    /// A status that is neither success nor an error (1xx, or a 3xx that was
+    /// not followed).  Nothing is passed through; the caller gets a `502`.
+    UnexpectedStatus { status: u16 },
+    /// A 2xx body could not be read in full or did not decode into the expected shape.
+    Decode { status: u16, error: String },
+}
+
+impl AttemptFailure {
+    /// Retry policy:
+    ///
+    /// * network errors, timeouts and 5xx responses are retried;
+    /// * 4xx responses are never retried, unless the operator opted in by
+    ///   listing a matching body pattern in `FAILOVER_ON_ERROR_PATTERNS`;
+    /// * other unexpected statuses (1xx, unfollowed 3xx) are not retried;
+    /// * read/decode failures (e.g. a truncated body) are retried once per
+    ///   call, since a repeat usually means the backend is returning a shape
+    ///   the gateway does not understand.
+    ///
+    /// Attempts are always bounded by `MAX_RETRIES` and the retry deadline.
+    fn retriable(&self, decode_retried: bool) -> bool {
+        match self {
+            AttemptFailure::Network(_)
+            | AttemptFailure::Timeout(_)
+            | AttemptFailure::ServerError { .. }
+            | AttemptFailure::FailoverPattern { .. } => true,
+            AttemptFailure::ClientError { .. } | AttemptFailure::UnexpectedStatus { .. } => false,
+            AttemptFailure::Decode { .. } => !decode_retried,
+        }
+    }
+
+    fn status(&self) -> Option<u16> {
+        match self {
+            AttemptFailure::Network(_) | AttemptFailure::Timeout(_) => None,
+            AttemptFailure::ServerError { status, .. }
+            | AttemptFailure::FailoverPattern { status, .. }
+            | AttemptFailure::ClientError { status, .. }
+            | AttemptFailure::UnexpectedStatus { status }
+            | AttemptFailure::Decode { status, .. } => Some(*status),
+        }
+    }
+
+    fn error(&self) -> Option<String> {
+        match self {
+            AttemptFailure::Network(error)
+            | AttemptFailure::Timeout(error)
+            | AttemptFailure::Decode { error, .. } => Some(error.clone()),
+            AttemptFailure::UnexpectedStatus { status } => {
+                Some(format!("Unexpected upstream status {}", status))
+            }
+            _ => None,
+        }
+    }
+
+    /// Category-specific fields for the `gateway.retry` event.
+    fn retry_details(&self) -> Value {
+        match self {
+            AttemptFailure::Network(error) => json!({
+                "status": "network_error",
+                "error": error,
+            }),
+            AttemptFailure::Timeout(error) => json!({
+                "status": "timeout",
+                "error": error,
+            }),
+            AttemptFailure::ServerError {
+                status,
+                body_snippet,
+            } => json!({
+                "status": status,
+                "reason": "upstream_server_error",
+                "body_snippet": body_snippet,
+            }),
+            AttemptFailure::FailoverPattern { status, pattern } => json!({
+                "status": status,
+                "reason": "failover_pattern",
+                "pattern": pattern,
+            }),
+            AttemptFailure::ClientError { status, .. } => json!({
+                "status": status,
+                "reason": "upstream_client_error",
+            }),
+            AttemptFailure::UnexpectedStatus { status } => json!({
+                "status": status,
+                "reason": "unexpected_status",
+            }),
+            AttemptFailure::Decode { status, error } => json!({
+                "status": status,
+                "reason": "decode_error",
+                "error": error,
+            }),
+        }
+    }
+}
+
+/// Describes a failure to decode a Python body by category and position only.
+/// serde's own message quotes the offending value, which may be user text the
+/// backend echoed back, so it must not reach logs.
+fn describe_parse_error(err: &serde_json::Error) -> String {
+    let category = match err.classify() {
+        serde_json::error::Category::Io => "I/O error",
+        serde_json::error::Category::Syntax => "invalid JSON",
+        serde_json::error::Category::Data => "unexpected shape",
+        serde_json::error::Category::Eof => "truncated JSON",
+    };
+    format!(
+        "{} at line {} column {}",
+        category,
+        err.line(),
+        err.column()
+    )
+}
+
+/// Decoded Python response along with the backend that produced it.
+#[derive(Clone)]
+struct Upstream<U> {
+    body: U,
+    backend: String,
+}
+
+/// Sends a request with the given method (and optional JSON body) to the Python
+/// backend, retrying failed attempts with exponential backoff according to
+/// `AttemptFailure::retriable`.
+///
+/// Backends are picked round-robin per request, and each retry moves on to the
+/// next configured backend so the retry loop doubles as client-side failover.
+///
+/// When `GATEWAY_RETRY_DEADLINE_MS` is set, the request is bounded by it as
+/// measured from `started`, when the gateway began handling it, so time spent
+/// queued for an upstream permit or waiting on a coalesced call counts too.
+/// Each attempt's timeout is capped to the remaining budget and no backoff is
+/// started that would overrun it.  The remaining budget is also sent as
+/// `X-Deadline-Ms` so Python can bound its own work (e.g. skip reranking when
+/// short on time).
+///
+/// `timeout` bounds each individual attempt.  Timed-out attempts are retried
+/// like other network errors; if the final attempt timed out the caller gets a
+/// `504` instead of a `502`.
+///
+/// `endpoint` is a bare path: it keys the retry statistics, so per-request
+/// parameters belong in `query`.
+async fn request_with_retry<T, U>(
+    client: &Client,
+    method: Method,
+    endpoint: &str,
+    query: &[(&str, String)],
+    payload: Option<&T>,
+    timeout: Option<Duration>,
+    started: Instant,
+) -> Result<Upstream<U>, HttpResponse>
+where
+    T: Serialize + ?Sized,
+    U: DeserializeOwned,
+{
+    let mut last_status: Option<u16> = None;
+    let mut last_error: Option<String> = None;
+    let backends = python_service_base_urls();
+    let first_backend = BACKEND_CURSOR.fetch_add(1, Ordering::Relaxed);
+    let deadline = env_parse::<u64>("GATEWAY_RETRY_DEADLINE_MS")
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis);
+    let fits_deadline =
+        |backoff: Duration| deadline.is_none_or(|deadline| started.elapsed() + backoff < deadline);
+    let log_deadline_exceeded = |attempt: usize, url: &str| {
+        log_gateway_event(
+            "gateway.retry_deadline_exceeded",
+            json!({
+                "url": url,
+                "method": method.as_str(),
+                "attempt": attempt + 1,
+                "elapsed_ms": started.elapsed().as_millis(),
+                "deadline_ms": deadline.map(|deadline| deadline.as_millis()),
+            }),
+        );
+    };
+
+    let request_texts = payload
+        .and_then(|payload| serde_json::to_value(payload).ok())
+        .map(|payload| payload_strings(&payload))
+        .unwrap_or_default();
+    let body = match payload.map(encode_upstream_body).transpose() {
+        Ok(body) => body,
+        Err(err) => {
+            return Err(versioned_json(
+                HttpResponse::InternalServerError(),
+                json!({ "error": format!("Failed to encode upstream request: {}", err) }),
+            ))
+        }
+    };
+
+    let mut decode_retried = false;
+    let mut timed_out = false;
+    for attempt in 0..MAX_RETRIES {
+        let backoff = retry_backoff(attempt);
+        let backend = &backends[(first_backend + attempt) % backends.len()];
+        let url = format!("{}{}", backend, endpoint);
+        let mut builder = client.request(method.clone(), &url).query(query);
+        if let Some((body, gzipped)) = &body {
+            builder = builder
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if *gzipped {
+                builder = builder.header(reqwest::header::CONTENT_ENCODING, "gzip");
+            }
+        }
+        let remaining = deadline.map(|deadline| deadline.saturating_sub(started.elapsed()));
+        if let Some(remaining) = remaining {
+            builder = builder.header(DEADLINE_HEADER, remaining.as_millis().to_string());
+        }
+        if let Some(attempt_timeout) = [timeout, remaining].into_iter().flatten().min() {
+            builder = builder.timeout(attempt_timeout);
+        }
+        let failure = match builder.send().await {
+            Err(err) if err.is_timeout() => AttemptFailure::Timeout(err.to_string()),
+            Err(err) => AttemptFailure::Network(err.to_string()),
+            Ok(resp) => {
+                let status = resp.status();
+                record_backend_queue_depth(resp.headers());
+                if status.is_server_error() {
+                    AttemptFailure::ServerError {
+                        status: status.as_u16(),
+                        body_snippet: error_body_snippet(resp, &request_texts).await,
+                    }
+                } else {
+                    match resp.bytes().await {
+                        Err(err) => AttemptFailure::Decode {
+                            status: status.as_u16(),
+                            error: format!("Failed to read upstream response: {}", err),
+                        },
+                        Ok(body) if status.is_success() => match serde_json::from_slice::<U>(&body)
+                        {
+                            Ok(body) => {
+                                if attempt > 0 {
+                                    record_retry_stats(endpoint, |stats| stats.recovered += 1);
+                                }
+                                return Ok(Upstream {
+                                    body,
+                                    backend: backend.clone(),
+                                });
+                            }
+                            Err(err) => AttemptFailure::Decode {
+                                status: status.as_u16(),
+                                error: format!(
+                                    "Failed to parse upstream response: {}",
+                                    describe_parse_error(&err)
+                                ),
+                            },
+                        },
+                        Ok(body) if status.is_client_error() => {
+                            match matching_failover_pattern(&body) {
+                                Some(pattern) => AttemptFailure::FailoverPattern {
+                                    status: status.as_u16(),
+                                    pattern,
+                                },
+                                None => AttemptFailure::ClientError {
+                                    status: status.as_u16(),
+                                    body: upstream_error_body(&body),
+                                },
+                            }
+                        }
+                        Ok(_) => AttemptFailure::UnexpectedStatus {
+                            status: status.as_u16(),
+                        },
+                    }
+                }
+            }
+        };
+
+        if let Some(status) = failure.status() {
+            last_status = Some(status);
+        }
+        last_error = failure.error();
+        timed_out = matches!(failure, AttemptFailure::Timeout(_));
+        if let AttemptFailure::ClientError { status, body } = failure {
+            return Err(upstream_rejection(status, body));
+        }
+        if !failure.retriable(decode_retried) {
+            break;
+        }
+        if attempt + 1 == MAX_RETRIES {
+            record_retry_stats(endpoint, |stats| stats.exhausted += 1);
+            break;
+        }
+        if !fits_deadline(backoff) {
+            last_error = Some("Retry deadline exceeded".to_string());
+            log_deadline_exceeded(attempt, &url);
+            break;
+        }
+        decode_retried |= matches!(failure, AttemptFailure::Decode { .. });
+        let mut details = json!({
+            "url": url,
+            "backend": backend,
+            "method": method.as_str(),
+            "attempt": attempt + 1,
+        });
+        if let (Some(details), Value::Object(reason)) =
+            (details.as_object_mut(), failure.retry_details())
+        {
+            details.extend(reason);
+        }
+        log_gateway_event("gateway.retry", details);
+        record_retry_stats(endpoint, |stats| stats.retries += 1);
+        sleep(backoff).await;
+    }
+
+    let (builder, error) = if timed_out {
+        (HttpResponse::GatewayTimeout(), "Python service timed out")
+    } else {
+        (HttpResponse::BadGateway(), "Failed to reach Python service")
+    };
+    Err(versioned_json(
+        builder,
+        json!({
+            "error": error,
+            "upstream_status": last_status,
+            "last_error": last_error,
+        }),
+    ))
+}
+
+/// Decodes a 4xx body from Python for passthrough: JSON as-is, anything else as
+/// a scrubbed, truncated string.
+fn upstream_error_body(body: &[u8]) -> Value {
+    serde_json::from_slice(body).unwrap_or_else(|_| {
+        Value::String(scrub_log_snippet(
+            &String::from_utf8_lossy(body),
+            DEFAULT_ERROR_BODY_LOG_CHARS,
+        ))
+    })
+}
+
+/// Forwards a Python 4xx to the client with its original status, wrapping the
+/// upstream error body in the gateway's error shape.
+fn upstream_rejection(status: u16, body: Value) -> HttpResponse {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_REQUEST);
+    versioned_json(
+        HttpResponse::build(status),
+        json!({
+            "error": "Python service rejected the request",
+            "upstream_status": status.as_u16(),
+            "upstream_error": body,
+        }),
+    )
+}
+
+/// Removes repeated citations (same source and text) when `DEDUP_CITATIONS=true`,
+/// keeping the first occurrence and returning how many were dropped.
+///
+/// Off by default so backend output is passed through untouched.
+fn dedup_citations(citations: Vec<Citation>) -> (Vec<Citation>, Option<usize>) {
+    if !env_flag("DEDUP_CITATIONS") {
+        return (citations, None);
+    }
+    let original = citations.len();
+    let mut seen = HashSet::new();
+    let unique: Vec<Citation> = citations
+        .into_iter()
+        .filter(|citation| seen.insert((citation.source.clone(), citation.text.clone())))
+        .collect();
+    let removed = original - unique.len();
+    (unique, Some(removed))
+}
+
+/// `CITATION_URL_TEMPLATE`, unless it is unset, empty or lacks the `{source}`
+/// placeholder (which would point every citation at the same URL).
+fn citation_url_template() -> Option<String> {
+    std::env::var("CITATION_URL_TEMPLATE")
+        .ok()
+        .filter(|template| template.contains("{source}"))
+}
+
+/// Reports a `CITATION_URL_TEMPLATE` that is set but ignored for lacking the
+/// `{source}` placeholder as a `gateway.config` warning.
+fn check_citation_url_template() {
+    let Ok(template) = std::env::var("CITATION_URL_TEMPLATE") else {
+        return;
+    };
+    if template.trim().is_empty() || citation_url_template().is_some() {
+        return;
+    }
+    log_gateway_event(
+        "gateway.config",
+        json!({
+            "setting": "CITATION_URL_TEMPLATE",
+            "warning": "template has no {source} placeholder; citation sources are left unchanged",
+        }),
+    );
+}
+
+/// Rewrites each citation's `source` through `CITATION_URL_TEMPLATE`, replacing
+/// its `{source}` placeholder (e.g. `https://docs.example.com{source}` turns
+/// `/store/doc_123` into a public URL).  Off when the variable is unset, empty
+/// or has no placeholder.
+fn rewrite_citation_sources(citations: &mut [Citation]) {
+    let Some(template) = citation_url_template() else {
+        return;
+    };
+    for citation in citations {
+        citation.source = template.replace("{source}", &citation.source);
+    }
+}
+
+/// Fetches shared state registered with `App::app_data` in `gateway_app`.
+fn shared<T: 'static>(req: &HttpRequest) -> &web::Data<T> {
+    req.app_data::<web::Data<T>>()
+        .expect("shared state is registered in gateway_app")
+}
+
+/// Builds the degraded `AskResponse` used when Python is unreachable and
+/// `GATEWAY_FALLBACK_ANSWER` is configured.
+///
+/// The status defaults to `200`; set `GATEWAY_FALLBACK_STATUS=503` to signal the
+/// outage while still returning the structured body.
+fn fallback_answer_response(
+    upstream_error: &HttpResponse,
+    request_id: &str,
+    latency_ms: u128,
+    session_id: Option<String>,
+) -> Option<HttpResponse> {
+    if !matches!(
+        upstream_error.status(),
+        StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT
+    ) {
+        return None;
+    }
+    let answer = std::env::var("GATEWAY_FALLBACK_ANSWER")
+        .ok()
+        .filter(|answer| !answer.trim().is_empty())?;
+    let status = env_parse::<u16>("GATEWAY_FALLBACK_STATUS")
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .unwrap_or(StatusCode::OK);
+    Some(versioned_json(
+        HttpResponse::build(status),
+        AskResponse {
+            request_id: request_id.to_owned(),
+            answer,
+            citations: Vec::new(),
+            latency_ms,
+            degraded: true,
+            session_id,
+            citations_deduped: None,
+            dry_run: false,
+            model: None,
+            backend_version: None,
+        },
+    ))
+}
+
+/// Handler for `POST /api/ask`.
+#[utoipa::path(
+    post,
+    path = "/api/ask",
+    request_body = AskRequest,
+    responses(
+        (status = 200, description = "Answer with citations", body = AskResponse),
+        (status = 400, description = "Malformed body or invalid field", body = ErrorResponse),
+        (status = 401, description = "Missing or unknown API key", body = ErrorResponse),
+        (status = 406, description = "`Accept` header excludes JSON", body = ErrorResponse),
+        (status = 415, description = "`Content-Type` is not `application/json`", body = ErrorResponse),
+        (status = 429, description = "Per-key rate limit or global request limit exceeded (`Retry-After` is set)", body = ErrorResponse),
+        (status = 422, description = "Rejected by the Python service; its error is passed through as `upstream_error`", body = ErrorResponse),
+        (status = 499, description = "Client closed the connection before the answer was ready", body = ErrorResponse),
+        (status = 502, description = "Python service unreachable", body = ErrorResponse),
+        (status = 503, description = "Shed while the backend reports a deep queue (`Retry-After` is set); or, with `GATEWAY_FALLBACK_STATUS=503`, the fallback answer as an `AskResponse` with `degraded: true`", body = ErrorResponse),
+        (status = 504, description = "Python service timed out", body = ErrorResponse),
+    ),
+    security(("api_key" = []))
+)]
+async fn ask_handler(http_req: HttpRequest, req: web::Json<AskRequest>) -> impl Responder {
+    process_ask(http_req, req.into_inner()).await
+}
+
+/// Handler for `GET /api/ask?query=...&top_k=...`, for clients that cannot send
+/// a JSON body.  Parameters are URL-decoded (`+` and percent-escapes) by the
+/// query extractor and then follow the exact same flow as the POST route.
+async fn ask_get_handler(http_req: HttpRequest, req: web::Query<AskRequest>) -> impl Responder {
+    process_ask(http_req, req.into_inner()).await
+}
+
+/// Shared ask flow: mints the request id, echoed as `X-Request-ID` on every
+/// response, then runs `answer_ask`.
+async fn process_ask(http_req: HttpRequest, req: AskRequest) -> HttpResponse {
+    let request_id = new_request_id();
+    let mut response = answer_ask(http_req, req, &request_id).await;
+    if let Ok(value) = header::HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(header::HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    response
+}
+
+/// Authentication, validation, caching and forwarding to Python for one ask.
+async fn answer_ask(http_req: HttpRequest, req: AskRequest, request_id: &str) -> HttpResponse {
+    let client = shared::<Client>(&http_req);
+    let cache = shared::<AskCache>(&http_req);
+    let key_profiles = shared::<KeyProfiles>(&http_req);
+    let app_stats = shared::<AppStats>(&http_req);
+    let rate_limiter = shared::<Option<RateLimiter>>(&http_req);
+    let upstream_limiter = shared::<UpstreamLimiter>(&http_req);
+    let metadata_limits = shared::<MetadataLimits>(&http_req);
+
+    let api_key = match extract_api_key(&http_req) {
+        Ok(key) => key,
+        Err(resp) => return resp,
+    };
+    let app_id = match extract_app_id(&http_req) {
+        Ok(app_id) => app_id,
+        Err(resp) => return resp,
+    };
+    app_stats.record(app_id.as_deref());
+    let caller = Caller::from_request(&http_req);
+    if let Err(resp) = enforce_rate_limit(&http_req, rate_limiter, &api_key) {
+        return resp;
+    }
+    let (req, profile_extras) = key_profiles.complete_request(&api_key, req);
+    if let Err(resp) = check_session_id(&http_req, req.session_id.as_deref()) {
+        return resp;
+    }
+    if let Err(resp) = check_metadata(&http_req, "filter", req.filter.as_ref(), metadata_limits) {
+        return resp;
+    }
+    if let Some(seed) = req.seed.filter(|seed| *seed > MAX_SEED) {
+        return reject_invalid(
+            &http_req,
+            "seed",
+            "max_value",
+            format!("seed {} exceeds the maximum of {}", seed, MAX_SEED),
+        );
+    }
+
+    let top_k = shared::<TopKLimits>(&http_req).resolve(req.top_k);
+    let max_answer_chars = answer_length_limit(req.max_answer_chars);
+    let start = Instant::now();
+
+    let query = req.query.clone();
+    let mut request_summary = redact_query(&query);
+    request_summary.insert("top_k".to_string(), json!(top_k));
+    if let Some(max_answer_chars) = max_answer_chars {
+        request_summary.insert("max_answer_chars".to_string(), json!(max_answer_chars));
+    }
+    if let Some(filter) = &req.filter {
+        let filter_keys: Vec<&String> = filter.keys().collect();
+        request_summary.insert("filter_keys".to_string(), json!(filter_keys));
+    }
+    let mut payload = json!({
+        "query": query,
+        "top_k": top_k
+    });
+    if let Some(model) = &req.model {
+        payload["model"] = json!(model);
+    }
+    if let Some(language) = &req.language {
+        payload["language"] = json!(language);
+    }
+    if let Some(session_id) = &req.session_id {
+        payload["session_id"] = json!(session_id);
+    }
+    if let Some(seed) = req.seed {
+        payload["seed"] = json!(seed);
+    }
+    if let Some(max_answer_chars) = max_answer_chars {
+        payload["max_answer_chars"] = json!(max_answer_chars);
+    }
+    if let Some(rerank) = req.rerank {
+        payload["rerank"] = json!(rerank);
+    }
+    if let Some(filter) = &req.filter {
+        payload["filter"] = json!(filter);
+    }
+    if let Value::Object(fields) = &mut payload {
+        fields.extend(profile_extras);
+    }
+    if env_flag("GATEWAY_DRY_RUN") {
+        let latency_ms = start.elapsed().as_millis();
+        log_gateway_event(
+            "gateway.request",
+            json!({
+                "path": "/api/ask",
+                "request_id": request_id,
+                "method": http_req.method().as_str(),
+                "status": 200,
+                "latency_ms": latency_ms,
+                "api_key_present": !api_key.is_empty(),
+                "app_id": app_id,
+                "client_ip": caller.ip,
+                "user_agent": caller.user_agent,
+                "dry_run": true,
+                "request": request_summary,
+            }),
+        );
+        return versioned_json(
+            HttpResponse::Ok(),
+            AskResponse {
+                request_id: request_id.to_owned(),
+                answer: DRY_RUN_ANSWER.to_string(),
+                citations: Vec::new(),
+                latency_ms,
+                degraded: false,
+                session_id: req.session_id.clone(),
+                citations_deduped: None,
+                dry_run: true,
+                model: None,
+                backend_version: None,
+            },
+        );
+    }
+    let cache_key = ask_cache_key(&payload);
+
+    // Answers in a conversation depend on server-side session context the
+    // payload does not capture, so they are never cached or shared.
+    let in_session = !payload["session_id"].is_null();
+    let bypass_cache = cache_bypass_requested(&http_req);
+    let cache_status = if !cache.enabled() {
+        "disabled"
+    } else if in_session {
+        "session"
+    } else if bypass_cache {
+        "bypass"
+    } else {
+        "miss"
+    };
+
+    if !bypass_cache && !in_session {
+        if let Some(body) = cache.get(&cache_key) {
+            let (mut citations, citations_deduped) = dedup_citations(body.citations);
+            rewrite_citation_sources(&mut citations);
+            let latency_ms = start.elapsed().as_millis();
+            let response = versioned_json(
+                HttpResponse::Ok(),
+                AskResponse {
+                    request_id: request_id.to_owned(),
+                    answer: body.answer,
+                    citations,
+                    latency_ms,
+                    degraded: false,
+                    session_id: req.session_id.clone(),
+                    citations_deduped,
+                    dry_run: false,
+                    model: body.model.clone(),
+                    backend_version: body.backend_version.clone(),
+                },
+            );
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/ask",
+                    "request_id": request_id,
+                    "method": http_req.method().as_str(),
+                    "status": response.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "client_ip": caller.ip,
+                    "user_agent": caller.user_agent,
+                    "cache": "hit",
+                    "model": body.model,
+                    "backend_version": body.backend_version,
+                    "request": request_summary,
+                }),
+            );
+            return response;
+        }
+    }
+
+    if let Err(resp) = shed_on_backend_queue_depth(&http_req) {
+        return resp;
+    }
+    if let Err(resp) = shared::<GlobalThrottle>(&http_req).enforce(&http_req) {
+        return resp;
+    }
+    let coalescer = shared::<AskCoalescer>(&http_req);
+    // An unregistered flight is never joined, and `finish`/`abandon` leave
+    // the coalescer untouched for it.
+    let flight = if in_session {
+        Arc::default()
+    } else {
+        coalescer.join(&cache_key)
+    };
+    let mut queue_wait_ms = None;
+    let mut coalesced = true;
+    let outcome = {
+        let (payload, queue_wait_ms, coalesced) = (&payload, &mut queue_wait_ms, &mut coalesced);
+        // Dropping the flight when the client hangs up aborts the in-flight
+        // Python call and any pending retry backoff; a coalesced follower, if
+        // any, takes the call over.
+        let call = flight.get_or_init(|| async move {
+            *coalesced = false;
+            let (_permit, wait_ms) = upstream_limiter.acquire(start).await;
+            *queue_wait_ms = wait_ms;
+            match post_with_retry::<_, PythonAskResponse>(
+                client,
+                PYTHON_ASK_ENDPOINT,
+                payload,
+                python_timeout("PYTHON_ASK_TIMEOUT_MS"),
+                start,
+            )
+            .await
+            {
+                Ok(upstream) => Ok(upstream),
+                Err(resp) => Err((
+                    resp.status(),
+                    actix_web::body::to_bytes(resp.into_body())
+                        .await
+                        .unwrap_or_default(),
+                )),
+            }
+        });
+        tokio::select! {
+            outcome = call => Some(outcome.clone()),
+            () = client_disconnected(&http_req) => None,
+        }
+    };
+    let Some(outcome) = outcome else {
+        coalescer.abandon(&cache_key, &flight);
+        log_gateway_event(
+            "gateway.client_cancelled",
+            json!({
+                "path": "/api/ask",
+                "request_id": request_id,
+                "method": http_req.method().as_str(),
+                "elapsed_ms": start.elapsed().as_millis(),
+                "app_id": app_id,
+                "client_ip": caller.ip,
+                "coalesced": coalesced,
+            }),
+        );
+        return versioned_json(
+            HttpResponse::build(
+                StatusCode::from_u16(CLIENT_CLOSED_REQUEST).unwrap_or(StatusCode::REQUEST_TIMEOUT),
+            ),
+            json!({ "error": "Client closed the request" }),
+        );
+    };
+    if let (Ok(upstream), false, false) = (&outcome, coalesced, in_session) {
+        cache.insert(&cache_key, &upstream.body);
+    }
+    coalescer.finish(&cache_key, &flight);
+    match outcome.map_err(|(status, body)| {
+        HttpResponse::build(status)
+            .content_type(header::ContentType::json())
+            .body(body)
+    }) {
+        Ok(Upstream { body, backend }) => {
+            let latency_ms = start.elapsed().as_millis();
+            let (mut citations, citations_deduped) = dedup_citations(body.citations);
+            rewrite_citation_sources(&mut citations);
+            let response = versioned_json(
+                HttpResponse::Ok(),
+                AskResponse {
+                    request_id: request_id.to_owned(),
+                    answer: body.answer,
+                    citations,
+                    latency_ms,
+                    degraded: false,
+                    session_id: req.session_id.clone(),
+                    citations_deduped,
+                    dry_run: false,
+                    model: body.model.clone(),
+                    backend_version: body.backend_version.clone(),
+                },
+            );
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/ask",
+                    "request_id": request_id,
+                    "method": http_req.method().as_str(),
+                    "status": response.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "client_ip": caller.ip,
+                    "user_agent": caller.user_agent,
+                    "queue_wait_ms": queue_wait_ms,
+                    "coalesced": coalesced,
+                    "backend": backend,
+                    "cache": cache_status,
+                    "model": body.model,
+                    "backend_version": body.backend_version,
+                    "request": request_summary,
+                }),
+            );
+            response
+        }
+        Err(resp) => {
+            let latency_ms = start.elapsed().as_millis();
+            if let Some(fallback) =
+                fallback_answer_response(&resp, request_id, latency_ms, req.session_id.clone())
+            {
+                log_gateway_event(
+                    "gateway.request",
+                    json!({
+                        "path": "/api/ask",
+                        "request_id": request_id,
+                        "method": http_req.method().as_str(),
+                        "status": fallback.status().as_u16(),
+                        "upstream_status": resp.status().as_u16(),
+                        "latency_ms": latency_ms,
+                        "api_key_present": !api_key.is_empty(),
+                        "app_id": app_id,
+                        "client_ip": caller.ip,
+                        "user_agent": caller.user_agent,
+                        "queue_wait_ms": queue_wait_ms,
+                        "coalesced": coalesced,
+                        "cache": cache_status,
+                        "degraded": true,
+                    }),
+                );
+                return fallback;
+            }
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/ask",
+                    "request_id": request_id,
+                    "method": http_req.method().as_str(),
+                    "status": resp.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "client_ip": caller.ip,
+                    "user_agent": caller.user_agent,
+                    "queue_wait_ms": queue_wait_ms,
+                    "coalesced": coalesced,
+                    "cache": cache_status,
+                }),
+            );
+            resp
+        }
+    }
+}
+
+/// Handler to forward document ingestion to the Python backend.
+#[utoipa::path(
+    post,
+    path = "/api/add_doc",
+    request_body = AddDocRequest,
+    params(("Idempotency-Key" = Option<String>, Header, description = "Replays the original result for a repeated request")),
+    responses(
+        (status = 200, description = "Document stored", body = AddDocResponse),
+        (status = 400, description = "Malformed body or metadata over limits", body = ErrorResponse),
+        (status = 401, description = "Missing or unknown API key", body = ErrorResponse),
+        (status = 406, description = "`Accept` header excludes JSON", body = ErrorResponse),
+        (status = 415, description = "`Content-Type` is not `application/json`", body = ErrorResponse),
+        (status = 429, description = "Per-key rate limit or global request limit exceeded (`Retry-After` is set)", body = ErrorResponse),
+        (status = 422, description = "Rejected by the Python service (its error is passed through as `upstream_error`), or `Idempotency-Key` reused with a different document", body = ErrorResponse),
+        (status = 502, description = "Python service unreachable", body = ErrorResponse),
+        (status = 503, description = "Gateway is in read-only mode", body = ErrorResponse),
+        (status = 504, description = "Python service timed out", body = ErrorResponse),
+    ),
+    security(("api_key" = []))
+)]
+async fn add_doc_handler(
+    http_req: HttpRequest,
+    client: web::Data<Client>,
+    metadata_limits: web::Data<MetadataLimits>,
+    idempotency: web::Data<IdempotencyStore>,
+    app_stats: web::Data<AppStats>,
+    rate_limiter: web::Data<Option<RateLimiter>>,
+    req: web::Json<AddDocRequest>,
+) -> impl Responder {
+    let api_key = match extract_api_key(&http_req) {
+        Ok(key) => key,
+        Err(resp) => return resp,
+    };
+    let app_id = match extract_app_id(&http_req) {
+        Ok(app_id) => app_id,
+        Err(resp) => return resp,
+    };
+    app_stats.record(app_id.as_deref());
+    let caller = Caller::from_request(&http_req);
+    if let Err(resp) = shared::<GatewayMode>(&http_req).reject_writes(&http_req) {
+        return resp;
+    }
+    if let Err(resp) = enforce_rate_limit(&http_req, &rate_limiter, &api_key) {
+        return resp;
+    }
+    if let Err(resp) = check_metadata(
+        &http_req,
+        "metadata",
+        req.metadata.as_ref(),
+        &metadata_limits,
+    ) {
+        return resp;
+    }
+
+    let start = Instant::now();
+    if env_flag("GATEWAY_DRY_RUN") {
+        let document_id = format!(
+            "dry-run-{}",
+            &format!("{:x}", Sha256::digest(req.text.as_bytes()))[..16]
+        );
+        let latency_ms = start.elapsed().as_millis();
+        log_gateway_event(
+            "gateway.request",
+            json!({
+                "path": "/api/add_doc",
+                "method": "POST",
+                "status": 200,
+                "latency_ms": latency_ms,
+                "api_key_present": !api_key.is_empty(),
+                "app_id": app_id,
+                "client_ip": caller.ip,
+                "user_agent": caller.user_agent,
+                "dry_run": true,
+                "document_id": document_id,
+                "request": redacted_summary("text", &req.text, false),
+            }),
+        );
+        return versioned_json(
+            HttpResponse::Ok(),
+            AddDocResponse {
+                document_id,
+                latency_ms,
+                dry_run: true,
+            },
+        );
+    }
+    let idempotency_key = idempotency_key(&http_req);
+    let reservation = match idempotency_key.as_deref() {
+        Some(key) => match idempotency.reserve(&api_key, key, &request_sha256(&*req)) {
+            Some(flight) => Some((key, flight)),
+            None => {
+                log_gateway_event(
+                    "gateway.idempotency_conflict",
+                    json!({
+                        "path": "/api/add_doc",
+                        "method": "POST",
+                        "app_id": app_id,
+                        "client_ip": caller.ip,
+                        "user_agent": caller.user_agent,
+                    }),
+                );
+                return versioned_json(
+                    HttpResponse::UnprocessableEntity(),
+                    json!({
+                        "error": format!(
+                            "{} was already used with a different document",
+                            IDEMPOTENCY_KEY_HEADER
+                        ),
+                        "field": IDEMPOTENCY_KEY_HEADER,
+                        "constraint": "same_body",
+                    }),
+                );
+            }
+        },
+        None => None,
+    };
+
+    let metadata_keys: Vec<String> = req
+        .metadata
+        .as_ref()
+        .map(|map| map.keys().cloned().collect())
+        .unwrap_or_default();
+    let mut request_summary = redacted_summary("text", &req.text, false);
+    request_summary.insert("metadata_keys".to_string(), json!(metadata_keys));
+
+    let mut queue_wait_ms = None;
+    let mut replayed = true;
+    let forward = async {
+        replayed = false;
+        shared::<GlobalThrottle>(&http_req).enforce(&http_req)?;
+        let (_permit, wait_ms) = shared::<UpstreamLimiter>(&http_req).acquire(start).await;
+        queue_wait_ms = wait_ms;
+        post_with_retry::<_, PythonAddDocResponse>(
+            &client,
+            PYTHON_ADD_DOC_ENDPOINT,
+            &*req,
+            python_timeout("PYTHON_ADD_DOC_TIMEOUT_MS"),
+            start,
+        )
+        .await
+    };
+    let result = match &reservation {
+        Some((key, flight)) => {
+            let result = flight.get_or_try_init(|| forward).await.cloned();
+            if result.is_err() {
+                idempotency.abandon(&api_key, key, flight);
+            }
+            result
+        }
+        None => forward.await,
+    };
+    match result {
+        Ok(Upstream { body, .. }) if replayed => {
+            let latency_ms = start.elapsed().as_millis();
+            log_gateway_event(
+                "gateway.idempotent_replay",
+                json!({
+                    "path": "/api/add_doc",
+                    "method": "POST",
+                    "app_id": app_id,
+                    "client_ip": caller.ip,
+                    "user_agent": caller.user_agent,
+                    "document_id": body.document_id,
+                    "latency_ms": latency_ms,
+                }),
+            );
+            versioned_json(
+                HttpResponse::Ok(),
+                AddDocResponse {
+                    document_id: body.document_id,
+                    latency_ms,
+                    dry_run: false,
+                },
+            )
+        }
+        Ok(Upstream { body, backend }) => {
+            let latency_ms = start.elapsed().as_millis();
+            let response = versioned_json(
+                HttpResponse::Ok(),
+                AddDocResponse {
+                    document_id: body.document_id,
+                    latency_ms,
+                    dry_run: false,
+                },
+            );
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/add_doc",
+                    "method": "POST",
+                    "status": response.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "client_ip": caller.ip,
+                    "user_agent": caller.user_agent,
+                    "queue_wait_ms": queue_wait_ms,
+                    "backend": backend,
+                    "request": request_summary,
+                }),
+            );
+            response
+        }
+        Err(resp) => {
+            let latency_ms = start.elapsed().as_millis();
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/add_doc",
+                    "method": "POST",
+                    "status": resp.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "client_ip": caller.ip,
+                    "user_agent": caller.user_agent,
+                    "queue_wait_ms": queue_wait_ms,
+                }),
+            );
+            resp
+        }
+    }
+}
+
+/// Handler that asks the Python backend to validate a document without ingesting it.
+async fn validate_doc_handler(
+    http_req: HttpRequest,
+    client: web::Data<Client>,
+    metadata_limits: web::Data<MetadataLimits>,
+    app_stats: web::Data<AppStats>,
+    rate_limiter: web::Data<Option<RateLimiter>>,
+    req: web::Json<AddDocRequest>,
+) -> impl Responder {
+    let api_key = match extract_api_key(&http_req) {
+        Ok(key) => key,
+        Err(resp) => return resp,
+    };
+    let app_id = match extract_app_id(&http_req) {
+        Ok(app_id) => app_id,
+        Err(resp) => return resp,
+    };
+    app_stats.record(app_id.as_deref());
+    if let Err(resp) = enforce_rate_limit(&http_req, &rate_limiter, &api_key) {
+        return resp;
+    }
+    if let Err(resp) = check_metadata(
+        &http_req,
+        "metadata",
+        req.metadata.as_ref(),
+        &metadata_limits,
+    ) {
+        return resp;
+    }
+
+    let start = Instant::now();
+    let metadata_keys: Vec<String> = req
+        .metadata
+        .as_ref()
+        .map(|map| map.keys().cloned().collect())
+        .unwrap_or_default();
+    let mut request_summary = redacted_summary("text", &req.text, false);
+    request_summary.insert("metadata_keys".to_string(), json!(metadata_keys));
+    let endpoint = python_endpoint_path("PYTHON_VALIDATE_DOC_PATH", PYTHON_VALIDATE_DOC_ENDPOINT);
+
+    let (_permit, queue_wait_ms) = shared::<UpstreamLimiter>(&http_req).acquire(start).await;
+    match post_with_retry::<_, PythonValidateDocResponse>(&client, &endpoint, &*req, None, start)
+        .await
+    {
+        Ok(Upstream { body, backend }) => {
+            let latency_ms = start.elapsed().as_millis();
+            let valid = body.valid;
+            let response = versioned_json(
+                HttpResponse::Ok(),
+                ValidateDocResponse {
+                    valid,
+                    issues: body.issues,
+                    latency_ms,
+                },
+            );
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/add_doc/validate",
+                    "method": "POST",
+                    "status": response.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "queue_wait_ms": queue_wait_ms,
+                    "backend": backend,
+                    "request": request_summary,
+                    "valid": valid,
+                }),
+            );
+            response
+        }
+        Err(resp) => {
+            let latency_ms = start.elapsed().as_millis();
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/add_doc/validate",
+                    "method": "POST",
+                    "status": resp.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "queue_wait_ms": queue_wait_ms,
+                }),
+            );
+            resp
+        }
+    }
+}
+
+/// Handler that proxies embedding requests to the Python backend.
+async fn embed_handler(
+    http_req: HttpRequest,
+    client: web::Data<Client>,
+    app_stats: web::Data<AppStats>,
+    rate_limiter: web::Data<Option<RateLimiter>>,
+    req: web::Json<EmbedRequest>,
+) -> impl Responder {
+    let api_key = match extract_api_key(&http_req) {
+        Ok(key) => key,
+        Err(resp) => return resp,
+    };
+    let app_id = match extract_app_id(&http_req) {
+        Ok(app_id) => app_id,
+        Err(resp) => return resp,
+    };
+    app_stats.record(app_id.as_deref());
+    if let Err(resp) = enforce_rate_limit(&http_req, &rate_limiter, &api_key) {
+        return resp;
+    }
+    if let Err(resp) = check_embed_texts(&http_req, &req.texts) {
+        return resp;
+    }
+
+    let start = Instant::now();
+    let request_summary = json!({
+        "texts": req.texts.len(),
+        "total_chars": req.texts.iter().map(|text| text.chars().count()).sum::<usize>(),
+    });
+    let endpoint = python_endpoint_path("PYTHON_EMBED_PATH", PYTHON_EMBED_ENDPOINT);
+
+    let (_permit, queue_wait_ms) = shared::<UpstreamLimiter>(&http_req).acquire(start).await;
+    match post_with_retry::<_, PythonEmbedResponse>(&client, &endpoint, &*req, None, start).await {
+        Ok(Upstream { body, backend }) => {
+            let latency_ms = start.elapsed().as_millis();
+            let response = versioned_json(
+                HttpResponse::Ok(),
+                EmbedResponse {
+                    embeddings: body.embeddings,
+                    latency_ms,
+                },
+            );
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/embed",
+                    "method": "POST",
+                    "status": response.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "queue_wait_ms": queue_wait_ms,
+                    "backend": backend,
+                    "request": request_summary,
+                }),
+            );
+            response
+        }
+        Err(resp) => {
+            let latency_ms = start.elapsed().as_millis();
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/embed",
+                    "method": "POST",
+                    "status": resp.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "queue_wait_ms": queue_wait_ms,
+                }),
+            );
+            resp
+        }
+    }
+}
+
+/// Handler listing ingested documents page by page, for corpus audits.
+async fn list_docs_handler(
+    http_req: HttpRequest,
+    client: web::Data<Client>,
+    app_stats: web::Data<AppStats>,
+    rate_limiter: web::Data<Option<RateLimiter>>,
+    query: web::Query<ListDocsQuery>,
+) -> impl Responder {
+    let api_key = match extract_api_key(&http_req) {
+        Ok(key) => key,
+        Err(resp) => return resp,
+    };
+    let app_id = match extract_app_id(&http_req) {
+        Ok(app_id) => app_id,
+        Err(resp) => return resp,
+    };
+    app_stats.record(app_id.as_deref());
+    if let Err(resp) = enforce_rate_limit(&http_req, &rate_limiter, &api_key) {
+        return resp;
+    }
+    let offset = match u64::try_from(query.offset.unwrap_or(0)) {
+        Ok(offset) => offset,
+        Err(_) => {
+            return reject_invalid(
+                &http_req,
+                "offset",
+                "non_negative",
+                "offset must not be negative".to_string(),
+            )
+        }
+    };
+    let max_limit = env_parse::<u64>("GATEWAY_MAX_LIST_DOCS_LIMIT")
+        .filter(|max| *max > 0)
+        .unwrap_or(DEFAULT_MAX_LIST_DOCS_LIMIT);
+    let limit = query
+        .limit
+        .map_or(DEFAULT_LIST_DOCS_LIMIT, |limit| limit.max(1) as u64)
+        .min(max_limit);
+
+    let start = Instant::now();
+    let endpoint = python_endpoint_path("PYTHON_LIST_DOCS_PATH", PYTHON_LIST_DOCS_ENDPOINT);
+    let query = [("limit", limit.to_string()), ("offset", offset.to_string())];
+
+    let (_permit, queue_wait_ms) = shared::<UpstreamLimiter>(&http_req).acquire(start).await;
+    match get_with_retry::<PythonListDocsResponse>(&client, &endpoint, &query, None, start).await {
+        Ok(Upstream { body, backend }) => {
+            let latency_ms = start.elapsed().as_millis();
+            let returned = body.documents.len();
+            let response = versioned_json(
+                HttpResponse::Ok(),
+                ListDocsResponse {
+                    documents: body.documents,
+                    limit,
+                    offset,
+                    latency_ms,
+                },
+            );
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/docs",
+                    "method": "GET",
+                    "status": response.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "queue_wait_ms": queue_wait_ms,
+                    "backend": backend,
+                    "limit": limit,
+                    "offset": offset,
+                    "documents": returned,
+                }),
+            );
+            response
+        }
+        Err(resp) => {
+            let latency_ms = start.elapsed().as_millis();
+            log_gateway_event(
+                "gateway.request",
+                json!({
+                    "path": "/api/docs",
+                    "method": "GET",
+                    "status": resp.status().as_u16(),
+                    "latency_ms": latency_ms,
+                    "api_key_present": !api_key.is_empty(),
+                    "app_id": app_id,
+                    "queue_wait_ms": queue_wait_ms,
+                }),
+            );
+            resp
+        }
+    }
+}
+
+/// Handler that forwards answer-quality feedback to Python and acknowledges
+/// it with `202 Accepted`.
+async fn feedback_handler(
+    http_req: HttpRequest,
+    client: web::Data<Client>,
+    app_stats: web::Data<AppStats>,
+    rate_limiter: web::Data<Option<RateLimiter>>,
+    req: web::Json<FeedbackRequest>,
+) -> impl Responder {
+    let api_key = match extract_api_key(&http_req) {
+        Ok(key) => key,
+        Err(resp) => return resp,
+    };
+    let app_id = match extract_app_id(&http_req) {
+        Ok(app_id) => app_id,
+        Err(resp) => return resp,
+    };
+    app_stats.record(app_id.as_deref());
+    if let Err(resp) = enforce_rate_limit(&http_req, &rate_limiter, &api_key) {
+        return resp;
+    }
+    if let Err(resp) = check_feedback(&http_req, &req) {
+        return resp;
+    }
+
+    let start = Instant::now();
+    let endpoint = python_endpoint_path("PYTHON_FEEDBACK_PATH", PYTHON_FEEDBACK_ENDPOINT);
+    let (_permit, queue_wait_ms) = shared::<UpstreamLimiter>(&http_req).acquire(start).await;
+    let result = post_with_retry::<_, Value>(&client, &endpoint, &*req, None, start).await;
+    let latency_ms = start.elapsed().as_millis();
+
+    // The comment is free text and stays out of the logs; only its length is kept.
+    let (response, backend) = match result {
+        Ok(Upstream { backend, .. }) => (
+            versioned_json(
+                HttpResponse::Accepted(),
+                json!({ "status": "accepted", "latency_ms": latency_ms }),
+            ),
+            Some(backend),
+        ),
+        Err(resp) => (resp, None),
+    };
+    log_gateway_event(
+        "gateway.feedback",
+        json!({
+            "path": "/api/feedback",
+            "method": "POST",
+            "status": response.status().as_u16(),
+            "latency_ms": latency_ms,
+            "api_key_present": !api_key.is_empty(),
+            "app_id": app_id,
+            "queue_wait_ms": queue_wait_ms,
+            "backend": backend,
+            "request_id": req.request_id,
+            "rating": req.rating.as_str(),
+            "comment_len": req.comment.as_ref().map(|comment| comment.chars().count()),
+        }),
+    );
+    response
+}
+
+/// Usage statistics endpoint (per-app request counts and upstream queue waits).
+async fn stats_handler(
+    http_req: HttpRequest,
+    app_stats: web::Data<AppStats>,
+    upstream_limiter: web::Data<UpstreamLimiter>,
+) -> impl Responder {
+    if let Err(resp) = extract_api_key(&http_req) {
+        return resp;
+    }
+    versioned_json(
+        HttpResponse::Ok(),
+        json!({
+            "apps": app_stats.snapshot(),
+            "max_tracked_apps": app_stats.max_apps,
+            "overflow_bucket": APP_OVERFLOW_BUCKET,
+            "queue_wait_ms": upstream_limiter.queue_wait_percentiles(),
+        }),
+    )
+}
+
+/// Human-readable instance status: uptime, request counters and configured
+/// backends.  Reads only in-process state, so it is cheap and never calls Python.
+async fn status_handler(
+    http_req: HttpRequest,
+    counters: web::Data<RequestCounters>,
+    global_throttle: web::Data<GlobalThrottle>,
+    mode: web::Data<GatewayMode>,
+    upstream_health: web::Data<UpstreamHealth>,
+) -> impl Responder {
+    if let Err(resp) = extract_api_key(&http_req) {
+        return resp;
+    }
+    let health = upstream_health.last_probe().map(|probe| {
+        json!({
+            "healthy": probe.healthy,
+            "error": probe.error,
+            "checked_at_unix_ms": probe
+                .probed_at_wall
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis()),
+            "checked_ms_ago": probe.probed_at.elapsed().as_millis(),
+        })
+    });
+    versioned_json(
+        HttpResponse::Ok(),
+        json!({
+            "uptime_secs": counters.started_at.elapsed().as_secs(),
+            "requests_total": counters.total.load(Ordering::Relaxed),
+            "requests_in_flight": counters.in_flight.load(Ordering::Relaxed),
+            "python_urls": python_service_base_urls(),
+            "retries": *RETRY_STATS.lock().unwrap(),
+            "global_rps": global_throttle.rps,
+            "global_throttled_total": global_throttle.throttled.load(Ordering::Relaxed),
+            "read_only": mode.read_only(),
+            "upstream_health": health,
+        }),
+    )
+}
+
+/// Serves the generated OpenAPI document.
+async fn openapi_handler() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+/// Swagger UI page for the OpenAPI document, loaded from a CDN.  Only routed
+/// when `GATEWAY_SWAGGER_UI` is enabled.
+async fn swagger_ui_handler() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(SWAGGER_UI_HTML)
+}
+
+/// Health check endpoint.  Liveness only: confirms the process is serving.
+async fn health_handler() -> impl Responder {
+    HttpResponse::Ok().body("OK")
+}
+
+/// Diagnostic produced by an end-to-end canary `/ask` round-trip.
+#[derive(Serialize)]
+struct SyntheticCheckResult {
+    passed: bool,
+    latency_ms: u128,
+    max_latency_ms: u64,
+    answer_length: usize,
+    citations: usize,
+    failures: Vec<String>,
+}
+
+/// Sends the configured canary query straight to Python's ask endpoint (with
+/// the usual retries) and checks the answer is non-empty and arrives within
+/// `GATEWAY_CANARY_MAX_LATENCY_MS`.  This is a backend round-trip only: the
+/// gateway's own ask handling (validation, key profiles, cache, coalescing,
+/// citation rewriting) is not exercised.
+async fn run_synthetic_check(client: &Client) -> SyntheticCheckResult {
+    let query = std::env::var("GATEWAY_CANARY_QUERY")
+        .ok()
+        .filter(|query| !query.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_CANARY_QUERY.to_string());
+    let max_latency_ms =
+        env_parse::<u64>("GATEWAY_CANARY_MAX_LATENCY_MS").unwrap_or(DEFAULT_CANARY_MAX_LATENCY_MS);
+    let payload = json!({ "query": query, "top_k": DEFAULT_TOP_K });
+
+    let start = Instant::now();
+    let result = post_with_retry::<_, PythonAskResponse>(
+        client,
+        PYTHON_ASK_ENDPOINT,
+        &payload,
+        python_timeout("PYTHON_ASK_TIMEOUT_MS"),
+        start,
+    )
+    .await;
+    let latency_ms = start.elapsed().as_millis();
+
+    let mut failures = Vec::new();
+    let (answer_length, citations) = match result {
+        Ok(Upstream { body, .. }) => {
+            if body.answer.trim().is_empty() {
+                failures.push("answer was empty".to_string());
+            }
+            (body.answer.len(), body.citations.len())
+        }
+        Err(resp) => {
+            failures.push(format!(
+                "upstream call failed with status {}",
+                resp.status().as_u16()
+            ));
+            (0, 0)
+        }
+    };
+    if latency_ms > u128::from(max_latency_ms) {
+        failures.push(format!(
+            "latency {}ms exceeded threshold {}ms",
+            latency_ms, max_latency_ms
+        ));
+    }
+
+    let result = SyntheticCheckResult {
+        passed: failures.is_empty(),
+        latency_ms,
+        max_latency_ms,
+        answer_length,
+        citations,
+        failures,
+    };
+    log_gateway_event(
+        "gateway.synthetic_check",
+        serde_json::to_value(&result).unwrap_or(Value::Null),
+    );
+    result
+}
+
+/// Admin endpoint running the synthetic canary check on demand.
+async fn synthetic_check_handler(
+    http_req: HttpRequest,
+    client: web::Data<Client>,
+) -> impl Responder {
+    if let Err(resp) = require_admin(&http_req) {
+        return resp;
+    }
+    let result = run_synthetic_check(&client).await;
+    let status = if result.passed {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    versioned_json(HttpResponse::build(status), result)
+}
+
+/// Warms the Python backend up before the gateway accepts traffic by posting a
+/// lightweight ask (or a body to `GATEWAY_WARMUP_PATH`) until it succeeds or
+/// `GATEWAY_WARMUP_DEADLINE_SECS` passes.  Each attempt is cut off at the
+/// deadline, so a backend that accepts connections but never answers cannot
+/// hold up startup.  Returns whether the backend answered.
+async fn run_warmup(client: &Client) -> bool {
+    let endpoint = python_endpoint_path("GATEWAY_WARMUP_PATH", PYTHON_ASK_ENDPOINT);
+    let deadline_secs =
+        env_parse::<u64>("GATEWAY_WARMUP_DEADLINE_SECS").unwrap_or(DEFAULT_WARMUP_DEADLINE_SECS);
+    let interval_ms =
+        env_parse::<u64>("GATEWAY_WARMUP_INTERVAL_MS").unwrap_or(DEFAULT_WARMUP_INTERVAL_MS);
+    let payload = json!({ "query": DEFAULT_CANARY_QUERY, "top_k": 1 });
+    log_gateway_event(
+        "gateway.warmup_started",
+        json!({ "endpoint": endpoint, "deadline_secs": deadline_secs }),
+    );
+
+    let start = Instant::now();
+    let deadline = Duration::from_secs(deadline_secs);
+    let mut attempts = 0u32;
+    loop {
+        attempts += 1;
+        let attempt = post_with_retry::<_, Value>(
+            client,
+            &endpoint,
+            &payload,
+            python_timeout("PYTHON_ASK_TIMEOUT_MS"),
+            Instant::now(),
+        );
+        let remaining = deadline.saturating_sub(start.elapsed());
+        let status = match tokio::time::timeout(remaining, attempt).await {
+            Ok(Ok(_)) => {
+                log_gateway_event(
+                    "gateway.warmup_ready",
+                    json!({
+                        "endpoint": endpoint,
+                        "attempts": attempts,
+                        "elapsed_ms": start.elapsed().as_millis(),
+                    }),
+                );
+                return true;
+            }
+            Ok(Err(resp)) => Some(resp.status().as_u16()),
+            Err(_) => None,
+        };
+        if start.elapsed() >= deadline {
+            log_gateway_event(
+                "gateway.warmup_failed",
+                json!({
+                    "endpoint": endpoint,
+                    "attempts": attempts,
+                    "elapsed_ms": start.elapsed().as_millis(),
+                    "last_status": status,
+                    "timed_out": status.is_none(),
+                }),
+            );
+            return false;
+        }
+        sleep(Duration::from_millis(interval_ms)).await;
+    }
+}
+
+/// Admin endpoint switching read-only mode on or off at runtime.
+async fn admin_mode_handler(
+    http_req: HttpRequest,
+    mode: web::Data<GatewayMode>,
+    req: web::Json<ModeRequest>,
+) -> impl Responder {
+    if let Err(resp) = require_admin(&http_req) {
+        return resp;
+    }
+    let previous = mode.read_only.swap(req.read_only, Ordering::Relaxed);
+    log_gateway_event(
+        "gateway.mode_changed",
+        json!({ "read_only": req.read_only, "previous_read_only": previous }),
+    );
+    versioned_json(HttpResponse::Ok(), json!({ "read_only": req.read_only }))
+}
+
+/// Outcome of a single probe against the Python `/health` endpoint.
+#[derive(Clone)]
+struct HealthProbe {
+    healthy: bool,
+    error: Option<String>,
+    probed_at: Instant,
+    /// Wall-clock time of the probe, for reporting.
+    probed_at_wall: SystemTime,
+}
+
+impl HealthProbe {
+    fn new(healthy: bool, error: Option<String>) -> Self {
+        HealthProbe {
+            healthy,
+            error,
+            probed_at: Instant::now(),
+            probed_at_wall: SystemTime::now(),
+        }
+    }
+}
+
+/// Cached upstream health shared by readiness checks so frequent probing does
+/// not translate into backend load.
+///
+/// Results are reused for `GATEWAY_HEALTH_CACHE_MS` and each probe is bounded
+/// by `GATEWAY_HEALTH_PROBE_TIMEOUT_MS`.  Refreshes are single-flight: once the
+/// cache expires one request probes Python while concurrent checks are served
+/// the stale result (or wait, when nothing has been probed yet).
+struct UpstreamHealth {
+    cache_ttl: Duration,
+    probe_timeout: Duration,
+    last_probe: Mutex<Option<HealthProbe>>,
+    refresh: AsyncMutex<()>,
+}
+
+impl UpstreamHealth {
+    fn from_env() -> Self {
+        UpstreamHealth {
+            cache_ttl: Duration::from_millis(
+                env_parse("GATEWAY_HEALTH_CACHE_MS").unwrap_or(DEFAULT_HEALTH_PROBE_CACHE_MS),
+            ),
+            probe_timeout: Duration::from_millis(
+                env_parse::<u64>("GATEWAY_HEALTH_PROBE_TIMEOUT_MS")
+                    .filter(|ms| *ms > 0)
+                    .unwrap_or(DEFAULT_HEALTH_PROBE_TIMEOUT_MS),
+            ),
+            last_probe: Mutex::new(None),
+            refresh: AsyncMutex::new(()),
+        }
+    }
+
+    /// The most recent probe, fresh or not.
+    fn last_probe(&self) -> Option<HealthProbe> {
+        self.last_probe.lock().unwrap().clone()
+    }
+
+    fn fresh_probe(&self) -> Option<HealthProbe> {
+        self.last_probe()
+            .filter(|probe| probe.probed_at.elapsed() < self.cache_ttl)
+    }
+
+    /// Returns the cached probe while fresh, otherwise probes Python again
+    /// unless another request already is.
+    async fn current(&self, client: &Client) -> HealthProbe {
+        if let Some(probe) = self.fresh_probe() {
+            return probe;
+        }
+        let _refresh = match (self.refresh.try_lock(), self.last_probe()) {
+            (Ok(guard), _) => guard,
+            (Err(_), Some(stale)) => return stale,
+            (Err(_), None) => self.refresh.lock().await,
+        };
+        if let Some(probe) = self.fresh_probe() {
+            return probe;
+        }
+        let probe = probe_upstream_health(client, self.probe_timeout).await;
+        *self.last_probe.lock().unwrap() = Some(probe.clone());
+        probe
+    }
+}
+
+/// Probes each configured Python backend's health endpoint with a short
+/// timeout; the upstream counts as healthy when any backend responds.
+async fn probe_upstream_health(client: &Client, timeout: Duration) -> HealthProbe {
+    let mut errors = Vec::new();
+    for backend in python_service_base_urls() {
+        let url = format!("{}{}", backend, PYTHON_HEALTH_ENDPOINT);
+        let result = client.get(&url).timeout(timeout).send().await;
+        match result {
+            Ok(resp) if resp.status().is_success() => {
+                return HealthProbe::new(true, None);
+            }
+            Ok(resp) => errors.push(format!(
+                "{} returned status {}",
+                backend,
+                resp.status().as_u16()
+            )),
+            Err(err) => errors.push(format!("{}: {}", backend, err)),
+        }
+    }
+    HealthProbe::new(false, Some(errors.join("; ")))
+}
+
+/// Readiness endpoint: `503` unless the (cached) upstream health probe succeeds.
+async fn ready_handler(
+    client: web::Data<Client>,
+    upstream_health: web::Data<UpstreamHealth>,
+) -> impl Responder {
+    let probe = upstream_health.current(&client).await;
+    let checked_ms_ago = probe.probed_at.elapsed().as_millis();
+    if probe.healthy {
+        versioned_json(
+            HttpResponse::Ok(),
+            json!({
+                "status": "ready",
+                "upstream": { "healthy": true, "checked_ms_ago": checked_ms_ago },
+            }),
+        )
+    } else {
+        log_gateway_event(
+            "gateway.not_ready",
+            json!({
+                "reason": "upstream_unhealthy",
+                "error": probe.error,
+            }),
+        );
+        versioned_json(
+            HttpResponse::ServiceUnavailable(),
+            json!({
+                "status": "not_ready",
+                "reason": "upstream_unhealthy",
+                "upstream": {
+                    "healthy": false,
+                    "error": probe.error,
+                    "checked_ms_ago": checked_ms_ago,
+                },
+            }),
+        )
+    }
+}
+
+/// Builds the rustls server config when `TLS_CERT_PATH` and `TLS_KEY_PATH` are
+/// both set (PEM certificate chain and private key).  Returns `None` to serve
+/// plain HTTP when neither is set, and an error when only one is set or the
+/// files cannot be read or parsed.
+fn load_tls_config() -> std::io::Result<Option<rustls::ServerConfig>> {
+    let cert_path = std::env::var("TLS_CERT_PATH")
+        .ok()
+        .filter(|path| !path.is_empty());
+    let key_path = std::env::var("TLS_KEY_PATH")
+        .ok()
+        .filter(|path| !path.is_empty());
+    let (cert_path, key_path) = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(std::io::Error::other(
+                "TLS_CERT_PATH and TLS_KEY_PATH must be set together",
+            ))
+        }
+    };
+    let tls_error = |path: &str, err: &dyn std::fmt::Display| {
+        std::io::Error::other(format!("Failed to load TLS file {}: {}", path, err))
+    };
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| tls_error(&cert_path, &err))?;
+    if certs.is_empty() {
+        return Err(tls_error(&cert_path, &"no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(&key_path).map_err(|err| tls_error(&key_path, &err))?;
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+    .map_err(|err| std::io::Error::other(format!("Invalid TLS configuration: {}", err)))?;
+    Ok(Some(config))
+}
+
+/// Worker count from `RUST_API_WORKERS`; `None` keeps the Actix default (one per
+/// logical CPU).  Zero or unparsable values are reported and ignored.
+fn configured_workers() -> Option<usize> {
+    let raw = std::env::var("RUST_API_WORKERS").ok()?;
+    match raw.trim().parse::<usize>() {
+        Ok(workers) if workers > 0 => Some(workers),
+        _ => {
+            log_gateway_event(
+                "gateway.config",
+                json!({
+                    "setting": "RUST_API_WORKERS",
+                    "warning": format!(
+                        "ignoring invalid worker count {:?}; expected a positive integer",
+                        raw
+                    ),
+                }),
+            );
+            None
+        }
+    }
+}
+
+/// Shared state registered with every worker's `App`, created once in `main`.
+#[derive(Clone)]
+struct GatewayState {
+    ask_cache: web::Data<AskCache>,
+    metadata_limits: web::Data<MetadataLimits>,
+    top_k_limits: web::Data<TopKLimits>,
+    idempotency: web::Data<IdempotencyStore>,
+    upstream_health: web::Data<UpstreamHealth>,
+    key_profiles: web::Data<KeyProfiles>,
+    app_stats: web::Data<AppStats>,
+    rate_limiter: web::Data<Option<RateLimiter>>,
+    request_counters: web::Data<RequestCounters>,
+    upstream_limiter: web::Data<UpstreamLimiter>,
+    ask_coalescer: web::Data<AskCoalescer>,
+    global_throttle: web::Data<GlobalThrottle>,
+    gateway_mode: web::Data<GatewayMode>,
+}
+
+impl GatewayState {
+    fn from_env() -> Self {
+        GatewayState {
+            ask_cache: web::Data::new(AskCache::from_env()),
+            metadata_limits: web::Data::new(MetadataLimits::from_env()),
+            top_k_limits: web::Data::new(TopKLimits::from_env()),
+            idempotency: web::Data::new(IdempotencyStore::from_env()),
+            upstream_health: web::Data::new(UpstreamHealth::from_env()),
+            key_profiles: web::Data::new(KeyProfiles::from_env()),
+            app_stats: web::Data::new(AppStats::from_env()),
+            rate_limiter: web::Data::new(RateLimiter::from_env()),
+            request_counters: web::Data::new(RequestCounters::new()),
+            upstream_limiter: web::Data::new(UpstreamLimiter::from_env()),
+            ask_coalescer: web::Data::new(AskCoalescer::new()),
+            global_throttle: web::Data::new(GlobalThrottle::from_env()),
+            gateway_mode: web::Data::new(GatewayMode::from_env()),
+        }
+    }
+}
+
+/// Builds one worker's `App`: middleware, shared state and routes.
+fn gateway_app(
+    state: &GatewayState,
+    swagger_ui: bool,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .wrap(from_fn(require_json_content_type))
+        .wrap(from_fn(negotiate_accept))
+        .wrap(from_fn(sign_responses))
+        .wrap(Compress::default())
+        .wrap(from_fn(count_requests))
+        .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+        .app_data(web::QueryConfig::default().error_handler(query_error_handler))
+        .app_data(web::Data::new(Client::new()))
+        .app_data(state.ask_cache.clone())
+        .app_data(state.metadata_limits.clone())
+        .app_data(state.top_k_limits.clone())
+        .app_data(state.idempotency.clone())
+        .app_data(state.upstream_health.clone())
+        .app_data(state.key_profiles.clone())
+        .app_data(state.app_stats.clone())
+        .app_data(state.rate_limiter.clone())
+        .app_data(state.request_counters.clone())
+        .app_data(state.upstream_limiter.clone())
+        .app_data(state.ask_coalescer.clone())
+        .app_data(state.global_throttle.clone())
+        .app_data(state.gateway_mode.clone())
+        .route("/api/ask", web::post().to(ask_handler))
+        .route("/api/ask", web::get().to(ask_get_handler))
+        .route("/api/add_doc", web::post().to(add_doc_handler))
+        .route(
+            "/api/add_doc/validate",
+            web::post().to(validate_doc_handler),
+        )
+        .route("/api/feedback", web::post().to(feedback_handler))
+        .route("/api/embed", web::post().to(embed_handler))
+        .route("/api/docs", web::get().to(list_docs_handler))
+        .route("/api/health", web::get().to(health_handler))
+        .route("/api/ready", web::get().to(ready_handler))
+        .route("/api/stats", web::get().to(stats_handler))
+        .route("/api/status", web::get().to(status_handler))
+        .route(
+            "/api/admin/synthetic_check",
+            web::get().to(synthetic_check_handler),
+        )
+        .route("/api/admin/mode", web::post().to(admin_mode_handler))
+        .route("/openapi.json", web::get().to(openapi_handler))
+        .configure(|cfg| {
+            if swagger_ui {
+                cfg.route("/swagger-ui", web::get().to(swagger_ui_handler));
+            }
+        })
+}
+
+/// Entry point.  Starts the Actix server and registers routes.
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let port = std::env::var("RUST_API_PORT")
+        .ok()
+        .and_then(|p| p.parse::<u16>().ok())
+        .unwrap_or(8000);
+    let tls_config = load_tls_config()?;
+    println!(
+        "Starting Rust API on port {} (schema_version {}, {})",
+        port,
+        SCHEMA_VERSION,
+        if tls_config.is_some() {
+            "TLS enabled"
+        } else {
+            "plain HTTP"
+        }
+    );
+    match API_KEY_ALLOWLIST.get_or_init(load_api_key_allowlist) {
+        Some(keys) => println!("API key allowlist enabled ({} keys)", keys.len()),
+        None => println!("API key allowlist disabled; any non-empty key is accepted"),
+    }
+    if env_flag("GATEWAY_DRY_RUN") {
+        println!("WARNING: GATEWAY_DRY_RUN is enabled; /api/ask and /api/add_doc return canned responses and never call Python");
+        log_gateway_event("gateway.dry_run_enabled", json!({}));
+    }
+    check_citation_url_template();
+    let state = GatewayState::from_env();
+    if state.gateway_mode.read_only() {
+        println!("Read-only mode enabled (GATEWAY_READ_ONLY); ingestion is rejected");
+    }
+    let swagger_ui = env_flag("GATEWAY_SWAGGER_UI");
+    if env_flag("GATEWAY_WARMUP")
+        && !run_warmup(&Client::new()).await
+        && env_flag("GATEWAY_WARMUP_REQUIRED")
+    {
+        return Err(std::io::Error::other(
+            "Python backend did not warm up before GATEWAY_WARMUP_DEADLINE_SECS",
+        ));
+    }
+    if state.rate_limiter.is_some() {
+        let rate_limiter = state.rate_limiter.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(RATE_LIMIT_EVICTION_SECS)).await;
+                if let Some(limiter) = rate_limiter.as_ref() {
+                    limiter.evict_idle();
+                }
+            }
+        });
+    }
+
+    if let Some(interval_secs) =
+        env_parse::<u64>("GATEWAY_CANARY_INTERVAL_SECS").filter(|secs| *secs > 0)
+    {
+        actix_web::rt::spawn(async move {
+            let client = Client::new();
+            loop {
+                sleep(Duration::from_secs(interval_secs)).await;
+                run_synthetic_check(&client).await;
+            }
+        });
+    }
+
+    let server = HttpServer::new(move || gateway_app(&state, swagger_ui));
+    let server = server.on_connect(capture_client_socket);
+    let workers = configured_workers();
+    let server = match workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let server = match tls_config {
+        Some(config) => server.bind_rustls_0_23(("127.0.0.1", port), config)?,
+        None => server.bind(("127.0.0.1", port))?,
+    };
+    println!(
+        "Listening on 127.0.0.1:{} (workers: {}{})",
+        port,
+        workers.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(2, std::num::NonZeroUsize::get)
+        }),
+        if workers.is_some() {
+            ", from RUST_API_WORKERS"
+        } else {
+            ""
+        }
+    );
+    server.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::{ServerHandle, Service};
+    use actix_web::test::{self, TestRequest};
+    use std::cell::RefCell;
+    use std::io::Read;
+    use std::rc::Rc;
+
+    const TEST_API_KEY: &str = "test-key";
+
+    /// Gateway configuration is read from process-wide environment variables,
+    /// so every test holds this lock for its whole run.
+    static ENV_LOCK: AsyncMutex<()> = AsyncMutex::const_new(());
+
+    /// Holds `ENV_LOCK` and restores every variable changed through it on drop.
+    struct TestEnv {
+        saved: Vec<(String, Option<String>)>,
+        _lock: tokio::sync::MutexGuard<'static, ()>,
+    }
+
+    impl TestEnv {
+        async fn lock() -> Self {
+            TestEnv {
+                saved: Vec::new(),
+                _lock: ENV_LOCK.lock().await,
+            }
+        }
+
+        fn set(&mut self, name: &str, value: &str) -> &mut Self {
+            if !self.saved.iter().any(|(saved, _)| saved == name) {
+                self.saved.push((name.to_owned(), std::env::var(name).ok()));
+            }
+            std::env::set_var(name, value);
+            self
+        }
+    }
+
+    impl Drop for TestEnv {
+        fn drop(&mut self) {
+            for (name, value) in self.saved.drain(..).rev() {
+                match value {
+                    Some(value) => std::env::set_var(&name, value),
+                    None => std::env::remove_var(&name),
+                }
+            }
+        }
+    }
+
+    thread_local! {
+        static CAPTURED_LOGS: RefCell<Option<Vec<Value>>> = const { RefCell::new(None) };
+    }
+
+    /// Called by `log_gateway_event`: keeps a copy of every event logged on
+    /// this thread while a `LogCapture` is active.  Handlers under
+    /// `test::call_service` run on the test's own thread.
+    pub(super) fn capture_log(entry: &Value) {
+        CAPTURED_LOGS.with(|logs| {
+            if let Some(logs) = logs.borrow_mut().as_mut() {
+                logs.push(entry.clone());
+            }
+        });
+    }
+
+    /// Collects the gateway events logged on the current thread until dropped.
+    struct LogCapture;
+
+    impl LogCapture {
+        fn start() -> Self {
+            CAPTURED_LOGS.with(|logs| *logs.borrow_mut() = Some(Vec::new()));
+            LogCapture
+        }
+
+        /// Details of every captured event named `event`.
+        fn events(&self, event: &str) -> Vec<Value> {
+            CAPTURED_LOGS.with(|logs| {
+                logs.borrow()
+                    .iter()
+                    .flatten()
+                    .filter(|entry| entry["event"] == event)
+                    .map(|entry| entry["details"].clone())
+                    .collect()
+            })
+        }
+
+        /// Every captured event, serialized.
+        fn text(&self) -> String {
+            CAPTURED_LOGS.with(|logs| serde_json::to_string(&*logs.borrow()).unwrap())
+        }
+    }
+
+    impl Drop for LogCapture {
+        fn drop(&mut self) {
+            CAPTURED_LOGS.with(|logs| *logs.borrow_mut() = None);
+        }
+    }
+
+    /// A request received by `MockPython`.
+    #[derive(Clone)]
+    struct MockRequest {
+        path: String,
+        query: String,
+        headers: header::HeaderMap,
+        body: web::Bytes,
+    }
+
+    impl MockRequest {
+        fn json(&self) -> Value {
+            serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+        }
+    }
+
+    /// What `MockPython` answers with.
+    #[derive(Clone)]
+    struct MockReply {
+        status: u16,
+        body: String,
+        headers: Vec<(&'static str, String)>,
+        delay: Duration,
+    }
+
+    impl MockReply {
+        fn json(status: u16, body: Value) -> Self {
+            MockReply {
+                status,
+                body: body.to_string(),
+                headers: Vec::new(),
+                delay: Duration::ZERO,
+            }
+        }
+
+        fn delayed(mut self, ms: u64) -> Self {
+            self.delay = Duration::from_millis(ms);
+            self
+        }
+
+        fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+            self.headers.push((name, value.to_string()));
+            self
+        }
+    }
+
+    /// Answers like a healthy Python backend.
+    fn python_reply(req: &MockRequest) -> MockReply {
+        match req.path.as_str() {
+            "/ask" => MockReply::json(
+                200,
+                json!({
+                    "answer": "42",
+                    "citations": [{ "source": "/store/doc_1", "text": "t" }],
+                }),
+            ),
+            "/add_doc" => MockReply::json(200, json!({ "document_id": "doc-1" })),
+            "/feedback" => MockReply::json(200, json!({ "status": "ok" })),
+            "/health" => MockReply::json(200, json!({ "status": "ok" })),
+            "/validate_doc" => MockReply::json(200, json!({ "valid": true })),
+            "/list_docs" => MockReply::json(
+                200,
+                json!({
+                    "documents": [
+                        { "document_id": "d1", "metadata": { "source": "playbook" } },
+                        { "document_id": "d2" },
+                    ],
+                }),
+            ),
+            _ => MockReply::json(404, json!({ "detail": "Not Found" })),
+        }
+    }
+
+    /// Stand-in for the Python service: an `HttpServer` on an ephemeral port
+    /// answering through `handler`, which also receives how many earlier
+    /// requests hit the same path.
+    struct MockPython {
+        url: String,
+        requests: Arc<Mutex<Vec<MockRequest>>>,
+        handle: ServerHandle,
+    }
+
+    impl MockPython {
+        async fn start(
+            handler: impl Fn(&MockRequest, usize) -> MockReply + Send + Sync + 'static,
+        ) -> Self {
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let handler = Arc::new(handler);
+            let recorded = requests.clone();
+            let server = HttpServer::new(move || {
+                let (recorded, handler) = (recorded.clone(), handler.clone());
+                App::new().default_service(web::to(move |req: HttpRequest, body: web::Bytes| {
+                    let (recorded, handler) = (recorded.clone(), handler.clone());
+                    async move {
+                        let request = MockRequest {
+                            path: req.path().to_owned(),
+                            query: req.query_string().to_owned(),
+                            headers: req.headers().clone(),
+                            body,
+                        };
+                        let earlier = {
+                            let mut recorded = recorded.lock().unwrap();
+                            recorded.push(request.clone());
+                            recorded.iter().filter(|r| r.path == request.path).count() - 1
+                        };
+                        let reply = handler(&request, earlier);
+                        sleep(reply.delay).await;
+                        let mut response =
+                            HttpResponse::build(StatusCode::from_u16(reply.status).unwrap());
+                        for header in reply.headers {
+                            response.insert_header(header);
+                        }
+                        response
+                            .content_type(header::ContentType::json())
+                            .body(reply.body)
+                    }
+                }))
+            })
+            .workers(1)
+            .disable_signals()
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+            let url = format!("http://{}", server.addrs()[0]);
+            let server = server.run();
+            let handle = server.handle();
+            actix_web::rt::spawn(server);
+            MockPython {
+                url,
+                requests,
+                handle,
+            }
+        }
+
+        fn hits(&self, path: &str) -> usize {
+            self.requests(path).len()
+        }
+
+        fn requests(&self, path: &str) -> Vec<MockRequest> {
+            let requests = self.requests.lock().unwrap();
+            requests
+                .iter()
+                .filter(|req| req.path == path)
+                .cloned()
+                .collect()
+        }
+    }
+
+    impl Drop for MockPython {
+        fn drop(&mut self) {
+            drop(self.handle.stop(false));
+        }
+    }
+
+    fn post_json(uri: &str, body: Value) -> TestRequest {
+        TestRequest::post()
+            .uri(uri)
+            .insert_header((API_KEY_HEADER, TEST_API_KEY))
+            .set_json(body)
+    }
+
+    fn get(uri: &str) -> TestRequest {
+        TestRequest::get()
+            .uri(uri)
+            .insert_header((API_KEY_HEADER, TEST_API_KEY))
+    }
+
+    /// Sends `req` through `app`, returning the status, headers and JSON body
+    /// (`Null` when the body is not JSON).
+    async fn call_json<S, R, B>(app: &S, req: R) -> (StatusCode, header::HeaderMap, Value)
+    where
+        S: Service<R, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let resp = test::call_service(app, req).await;
+        let (status, headers) = (resp.status(), resp.headers().clone());
+        let body = test::read_body(resp).await;
+        (
+            status,
+            headers,
+            serde_json::from_slice(&body).unwrap_or(Value::Null),
+        )
+    }
+
+    /// Sends every request through `app` at once, returning statuses and JSON
+    /// bodies in request order.
+    async fn call_concurrently<S, R, B>(app: &Rc<S>, requests: Vec<R>) -> Vec<(StatusCode, Value)>
+    where
+        S: Service<R, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+        R: 'static,
+        B: MessageBody + 'static,
+    {
+        let calls: Vec<_> = requests
+            .into_iter()
+            .map(|req| {
+                let app = app.clone();
+                actix_web::rt::spawn(async move {
+                    let (status, _, body) = call_json(&*app, req).await;
+                    (status, body)
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for call in calls {
+            results.push(call.await.unwrap());
+        }
+        results
+    }
+
+    #[actix_web::test]
+    async fn upstream_client_errors_keep_their_status_and_body() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| match req.path.as_str() {
+            "/ask" => MockReply::json(400, json!({ "error": "invalid_query" })),
+            "/add_doc" => MockReply::json(
+                422,
+                json!({ "detail": [{ "loc": ["body", "text"], "msg": "too short" }] }),
+            ),
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+
+        let ask = post_json("/api/ask", json!({ "query": "hi" })).to_request();
+        let (status, _, body) = call_json(&app, ask).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["upstream_status"], 400);
+        assert_eq!(body["upstream_error"], json!({ "error": "invalid_query" }));
+        assert_eq!(python.hits("/ask"), 1, "4xx must not be retried");
+
+        let add_doc = post_json("/api/add_doc", json!({ "text": "x" })).to_request();
+        let (status, _, body) = call_json(&app, add_doc).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["upstream_status"], 422);
+        assert_eq!(body["upstream_error"]["detail"][0]["msg"], "too short");
+        assert_eq!(python.hits("/add_doc"), 1);
+    }
+
+    #[actix_web::test]
+    async fn unexpected_upstream_statuses_are_not_passed_through() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|_, _| MockReply::json(304, json!({}))).await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+
+        let ask = post_json("/api/ask", json!({ "query": "hi" })).to_request();
+        let (status, _, body) = call_json(&app, ask).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["upstream_status"], 304);
+        assert!(body.get("upstream_error").is_none());
+        assert_eq!(python.hits("/ask"), 1);
+    }
+
+    #[actix_web::test]
+    async fn list_docs_sends_paging_as_query_and_keys_retry_stats_by_path() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, earlier| match req.path.as_str() {
+            "/list_docs" if earlier == 0 => MockReply::json(500, json!({ "detail": "warming up" })),
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_BACKOFF_MS", "1");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+
+        let (status, _, body) =
+            call_json(&app, get("/api/docs?limit=2&offset=1").to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["limit"], 2);
+        assert_eq!(body["offset"], 1);
+        assert_eq!(body["documents"][0]["document_id"], "d1");
+        assert_eq!(body["documents"][0]["metadata"]["source"], "playbook");
+        assert_eq!(body["documents"][1]["document_id"], "d2");
+
+        let requests = python.requests("/list_docs");
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|req| req.query == "limit=2&offset=1"));
+        let stats = RETRY_STATS.lock().unwrap();
+        assert!(stats
+            .get("/list_docs")
+            .is_some_and(|stats| stats.recovered > 0));
+        assert!(stats.keys().all(|endpoint| !endpoint.contains('?')));
+    }
+
+    #[actix_web::test]
+    async fn warmup_succeeds_against_a_healthy_backend() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url);
+
+        assert!(run_warmup(&Client::new()).await);
+        assert_eq!(python.hits("/ask"), 1);
+    }
+
+    #[actix_web::test]
+    async fn warmup_gives_up_at_the_deadline_when_the_backend_hangs() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req).delayed(10_000)).await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_WARMUP_DEADLINE_SECS", "1");
+
+        let started = Instant::now();
+        assert!(!run_warmup(&Client::new()).await);
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[actix_web::test]
+    async fn expired_cache_entry_is_refreshed_by_one_backend_call() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req).delayed(100)).await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_ASK_CACHE_TTL_MS", "200");
+        let app = Rc::new(test::init_service(gateway_app(&GatewayState::from_env(), false)).await);
+        let ask = || post_json("/api/ask", json!({ "query": "hot question" })).to_request();
+
+        let (status, _, _) = call_json(&*app, ask()).await;
+        assert_eq!(status, StatusCode::OK);
+        sleep(Duration::from_millis(250)).await;
+
+        let results = call_concurrently(&app, (0..8).map(|_| ask()).collect()).await;
+        assert!(results
+            .iter()
+            .all(|(status, body)| *status == StatusCode::OK && body["answer"] == "42"));
+        assert_eq!(python.hits("/ask"), 2, "one call to fill, one to refresh");
+
+        let (status, _, _) = call_json(&*app, ask()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            python.hits("/ask"),
+            2,
+            "the refreshed entry is served from cache"
+        );
+    }
+
+    #[actix_web::test]
+    async fn failed_cache_refresh_is_shared_with_waiting_requests() {
+        let mut env = TestEnv::lock().await;
+        let python =
+            MockPython::start(|_, _| MockReply::json(500, json!({ "detail": "down" })).delayed(50))
+                .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_ASK_CACHE_TTL_MS", "60000")
+            .set("GATEWAY_MAX_BACKOFF_MS", "1");
+        let app = Rc::new(test::init_service(gateway_app(&GatewayState::from_env(), false)).await);
+        let ask = || post_json("/api/ask", json!({ "query": "cold question" })).to_request();
+
+        let results = call_concurrently(&app, (0..8).map(|_| ask()).collect()).await;
+        assert!(results
+            .iter()
+            .all(|(status, _)| *status == StatusCode::BAD_GATEWAY));
+        assert_eq!(python.hits("/ask"), MAX_RETRIES, "a single retry cycle");
+    }
+
+    #[actix_web::test]
+    async fn concurrent_requests_with_one_idempotency_key_ingest_once() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, earlier| match req.path.as_str() {
+            "/add_doc" => MockReply::json(
+                200,
+                json!({ "document_id": format!("doc-{}", earlier + 1) }),
+            )
+            .delayed(100),
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = Rc::new(test::init_service(gateway_app(&GatewayState::from_env(), false)).await);
+        let add_doc = |key: &str, text: &str| {
+            post_json(
+                "/api/add_doc",
+                json!({ "text": text, "metadata": { "a": "1", "b": "2" } }),
+            )
+            .insert_header((IDEMPOTENCY_KEY_HEADER, key))
+            .to_request()
+        };
+
+        let results =
+            call_concurrently(&app, (0..4).map(|_| add_doc("key-1", "same doc")).collect()).await;
+        assert!(results
+            .iter()
+            .all(|(status, body)| *status == StatusCode::OK && body["document_id"] == "doc-1"));
+        assert_eq!(python.hits("/add_doc"), 1);
+
+        let (status, _, body) = call_json(&*app, add_doc("key-1", "same doc")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["document_id"], "doc-1");
+
+        let (status, _, body) = call_json(&*app, add_doc("key-1", "another doc")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["field"], IDEMPOTENCY_KEY_HEADER);
+        assert_eq!(python.hits("/add_doc"), 1);
+
+        let (status, _, body) = call_json(&*app, add_doc("key-2", "another doc")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["document_id"], "doc-2");
+    }
+
+    #[actix_web::test]
+    async fn failed_ingestion_releases_its_idempotency_key() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, earlier| match req.path.as_str() {
+            "/add_doc" if earlier == 0 => MockReply::json(400, json!({ "error": "busy" })),
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let add_doc = |text: &str| {
+            post_json("/api/add_doc", json!({ "text": text }))
+                .insert_header((IDEMPOTENCY_KEY_HEADER, "retry-me"))
+                .to_request()
+        };
+
+        let (status, _, _) = call_json(&app, add_doc("first try")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _, body) = call_json(&app, add_doc("corrected doc")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["document_id"], "doc-1");
+        assert_eq!(python.hits("/add_doc"), 2);
+    }
+
+    #[actix_web::test]
+    async fn asks_return_a_request_id_that_feedback_accepts() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+        let ask = || post_json("/api/ask", json!({ "query": "hi" })).to_request();
+
+        let (status, headers, body) = call_json(&app, ask()).await;
+        assert_eq!(status, StatusCode::OK);
+        let request_id = body["request_id"].as_str().unwrap().to_owned();
+        assert!(is_request_id(&request_id));
+        assert_eq!(headers.get(REQUEST_ID_HEADER).unwrap(), request_id.as_str());
+        let (_, _, second) = call_json(&app, ask()).await;
+        assert_ne!(second["request_id"], request_id.as_str());
+        assert_eq!(
+            logs.events("gateway.request")[0]["request_id"],
+            request_id.as_str()
+        );
+
+        let feedback = post_json(
+            "/api/feedback",
+            json!({ "request_id": request_id, "rating": "up", "comment": "great" }),
+        );
+        let (status, _, _) = call_json(&app, feedback.to_request()).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let logged = logs.events("gateway.feedback");
+        assert_eq!(logged[0]["request_id"], request_id.as_str());
+        assert!(logged[0].get("comment").is_none());
+
+        let free_text = post_json(
+            "/api/feedback",
+            json!({ "request_id": "my private note", "rating": "down" }),
+        );
+        let (status, _, body) = call_json(&app, free_text.to_request()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["constraint"], "format");
+        assert_eq!(python.hits("/feedback"), 1);
+        assert!(
+            !serde_json::to_string(&logs.events("gateway.validation_failed"))
+                .unwrap()
+                .contains("my private note")
+        );
+    }
+
+    #[actix_web::test]
+    async fn asks_without_a_session_are_cached_and_echo_no_session() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_ASK_CACHE_TTL_MS", "60000");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+
+        for _ in 0..2 {
+            let ask = post_json("/api/ask", json!({ "query": "standalone" })).to_request();
+            let (status, _, body) = call_json(&app, ask).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(body.get("session_id").is_none());
+        }
+        assert_eq!(python.hits("/ask"), 1);
+        assert!(python.requests("/ask")[0]
+            .json()
+            .get("session_id")
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn asks_in_a_session_skip_the_cache_and_coalescing() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req).delayed(50)).await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_ASK_CACHE_TTL_MS", "60000");
+        let app = Rc::new(test::init_service(gateway_app(&GatewayState::from_env(), false)).await);
+        let ask = || {
+            post_json(
+                "/api/ask",
+                json!({ "query": "and then?", "session_id": "conv-1" }),
+            )
+            .to_request()
+        };
+
+        let (status, _, body) = call_json(&*app, ask()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["session_id"], "conv-1");
+        let results = call_concurrently(&app, (0..3).map(|_| ask()).collect()).await;
+        assert!(results
+            .iter()
+            .all(|(status, body)| *status == StatusCode::OK && body["session_id"] == "conv-1"));
+        assert_eq!(python.hits("/ask"), 4);
+        assert_eq!(python.requests("/ask")[0].json()["session_id"], "conv-1");
+    }
+
+    #[actix_web::test]
+    async fn citation_sources_are_rewritten_through_the_template() {
+        let mut env = TestEnv::lock().await;
+        let citations = || {
+            vec![Citation {
+                source: "/store/doc_123".to_string(),
+                text: "t".to_string(),
+            }]
+        };
+
+        let mut unchanged = citations();
+        rewrite_citation_sources(&mut unchanged);
+        assert_eq!(unchanged[0].source, "/store/doc_123");
+
+        env.set(
+            "CITATION_URL_TEMPLATE",
+            "https://docs.example.com{source}?ref=gw",
+        );
+        let mut rewritten = citations();
+        rewrite_citation_sources(&mut rewritten);
+        assert_eq!(
+            rewritten[0].source,
+            "https://docs.example.com/store/doc_123?ref=gw"
+        );
+    }
+
+    #[actix_web::test]
+    async fn citation_template_without_placeholder_is_ignored_with_a_warning() {
+        let mut env = TestEnv::lock().await;
+        env.set("CITATION_URL_TEMPLATE", "https://docs.example.com/");
+        let logs = LogCapture::start();
+
+        check_citation_url_template();
+        let warnings = logs.events("gateway.config");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0]["setting"], "CITATION_URL_TEMPLATE");
+
+        let mut citations = vec![Citation {
+            source: "/store/doc_1".to_string(),
+            text: "t".to_string(),
+        }];
+        rewrite_citation_sources(&mut citations);
+        assert_eq!(citations[0].source, "/store/doc_1");
+    }
+
+    #[actix_web::test]
+    async fn retry_deadline_counts_time_queued_for_an_upstream_permit() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, earlier| match req.path.as_str() {
+            "/ask" if earlier == 0 => python_reply(req).delayed(400),
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_RETRY_DEADLINE_MS", "2000")
+            .set("GATEWAY_MAX_CONCURRENT_UPSTREAM", "1");
+        let app = Rc::new(test::init_service(gateway_app(&GatewayState::from_env(), false)).await);
+
+        let asks = ["first", "second"]
+            .iter()
+            .map(|query| post_json("/api/ask", json!({ "query": query })).to_request())
+            .collect();
+        for (status, _) in call_concurrently(&app, asks).await {
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let budgets: Vec<u64> = python
+            .requests("/ask")
+            .iter()
+            .map(|req| {
+                let budget = req.headers.get(DEADLINE_HEADER).expect("deadline header");
+                budget.to_str().unwrap().parse().unwrap()
+            })
+            .collect();
+        assert_eq!(budgets.len(), 2);
+        assert!(budgets[0] <= 2000, "first budget {}", budgets[0]);
+        assert!(
+            budgets[1] <= 2000 - 300,
+            "queued ask must not get the full budget, got {}",
+            budgets[1]
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        // Test case 1: a key shorter than the block.
+        assert_eq!(
+            hmac_sha256_hex(&[0x0b; 20], b"Hi There"),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        // Test case 6: a key longer than the block is hashed first.
+        assert_eq!(
+            hmac_sha256_hex(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[actix_web::test]
+    async fn responses_are_signed_over_the_body_bytes() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_SIGNING_SECRET", "s3cret");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+
+        let resp = test::call_service(&app, get("/api/docs").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let signature = resp.headers().get(SIGNATURE_HEADER).unwrap().clone();
+        let body = test::read_body(resp).await;
+        assert_eq!(signature, hmac_sha256_hex(b"s3cret", &body).as_str());
+    }
+
+    #[actix_web::test]
+    async fn concurrent_identical_asks_share_one_backend_call() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req).delayed(200)).await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = Rc::new(test::init_service(gateway_app(&GatewayState::from_env(), false)).await);
+        let logs = LogCapture::start();
+
+        let asks = (0..5)
+            .map(|_| post_json("/api/ask", json!({ "query": "same question" })).to_request())
+            .collect();
+        for (status, body) in call_concurrently(&app, asks).await {
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["answer"], "42");
+        }
+        assert_eq!(python.hits("/ask"), 1);
+        let coalesced = logs
+            .events("gateway.request")
+            .iter()
+            .filter(|event| event["coalesced"] == true)
+            .count();
+        assert_eq!(coalesced, 4);
+
+        let other = post_json("/api/ask", json!({ "query": "other question" }));
+        let (status, _, _) = call_json(&*app, other.to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(python.hits("/ask"), 2);
+    }
+
+    #[actix_web::test]
+    async fn coalesced_asks_share_a_backend_failure() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|_, _| {
+            MockReply::json(503, json!({ "detail": "overloaded" })).delayed(100)
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_BACKOFF_MS", "1");
+        let app = Rc::new(test::init_service(gateway_app(&GatewayState::from_env(), false)).await);
+
+        let asks = (0..4)
+            .map(|_| post_json("/api/ask", json!({ "query": "same question" })).to_request())
+            .collect();
+        let results = call_concurrently(&app, asks).await;
+        assert_eq!(python.hits("/ask"), MAX_RETRIES);
+        let (status, body) = &results[0];
+        assert!(status.is_server_error());
+        for result in &results[1..] {
+            assert_eq!(result, &(*status, body.clone()));
+        }
+    }
+
+    #[actix_web::test]
+    async fn follower_takes_over_an_ask_whose_leader_went_away() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, earlier| match req.path.as_str() {
+            "/ask" if earlier == 0 => python_reply(req).delayed(5_000),
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = Rc::new(test::init_service(gateway_app(&GatewayState::from_env(), false)).await);
+        let ask = || post_json("/api/ask", json!({ "query": "same question" })).to_request();
+
+        let leader = {
+            let (app, req) = (app.clone(), ask());
+            actix_web::rt::spawn(async move { call_json(&*app, req).await })
+        };
+        while python.hits("/ask") == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let follower = {
+            let (app, req) = (app.clone(), ask());
+            actix_web::rt::spawn(async move { call_json(&*app, req).await })
+        };
+        sleep(Duration::from_millis(50)).await;
+        leader.abort();
+
+        let (status, _, body) = tokio::time::timeout(Duration::from_secs(2), follower)
+            .await
+            .expect("follower must not wait for the abandoned call")
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["answer"], "42");
+        assert_eq!(python.hits("/ask"), 2);
+    }
+
+    #[test]
+    fn ask_coalescer_only_forgets_calls_nobody_waits_on() {
+        let coalescer = AskCoalescer::new();
+        let leader = coalescer.join("k");
+        let follower = coalescer.join("k");
+        assert!(Arc::ptr_eq(&leader, &follower));
+
+        coalescer.abandon("k", &leader);
+        drop(leader);
+        assert!(Arc::ptr_eq(&coalescer.join("k"), &follower));
+
+        coalescer.abandon("k", &follower);
+        drop(follower);
+        let fresh = coalescer.join("k");
+        let newer = {
+            coalescer.finish("k", &fresh);
+            coalescer.join("k")
+        };
+        assert!(!Arc::ptr_eq(&fresh, &newer));
+        coalescer.finish("k", &fresh);
+        assert!(Arc::ptr_eq(&coalescer.join("k"), &newer));
+    }
+
+    #[actix_web::test]
+    async fn concurrent_readiness_checks_share_one_health_probe() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req).delayed(200)).await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_HEALTH_CACHE_MS", "100");
+        let app = Rc::new(test::init_service(gateway_app(&GatewayState::from_env(), false)).await);
+        let checks = || (0..5).map(|_| get("/api/ready").to_request()).collect();
+
+        // Cold: nothing probed yet, so everyone waits on the one probe.
+        for (status, body) in call_concurrently(&app, checks()).await {
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["upstream"]["healthy"], true);
+        }
+        assert_eq!(python.hits("/health"), 1);
+
+        // Stale: one request refreshes while the others get the old result.
+        sleep(Duration::from_millis(150)).await;
+        let started = Instant::now();
+        for (status, _) in call_concurrently(&app, checks()).await {
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(python.hits("/health"), 2);
+    }
+
+    #[actix_web::test]
+    async fn failures_are_retried_according_to_their_category() {
+        let mut env = TestEnv::lock().await;
+        env.set("GATEWAY_MAX_BACKOFF_MS", "1")
+            .set("PYTHON_ADD_DOC_TIMEOUT_MS", "100");
+        let add_doc = || post_json("/api/add_doc", json!({ "text": "doc" })).to_request();
+        let cases = [
+            (
+                "server_error",
+                MockReply::json(500, json!({ "detail": "boom" })),
+                StatusCode::BAD_GATEWAY,
+                MAX_RETRIES,
+            ),
+            (
+                "timeout",
+                MockReply::json(200, json!({ "document_id": "doc-1" })).delayed(500),
+                StatusCode::GATEWAY_TIMEOUT,
+                MAX_RETRIES,
+            ),
+            (
+                "client_error",
+                MockReply::json(404, json!({ "detail": "nope" })),
+                StatusCode::NOT_FOUND,
+                1,
+            ),
+            (
+                "decode",
+                MockReply::json(200, json!({ "unexpected": true })),
+                StatusCode::BAD_GATEWAY,
+                2,
+            ),
+        ];
+        for (category, reply, expected, hits) in cases {
+            let python = MockPython::start(move |_, _| reply.clone()).await;
+            env.set("PYTHON_AI_URL", &python.url);
+            let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+            let (status, _, _) = call_json(&app, add_doc()).await;
+            assert_eq!(status, expected, "{}", category);
+            assert_eq!(python.hits("/add_doc"), hits, "{}", category);
+        }
+
+        // Nothing listening: every attempt fails to connect.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", closed.local_addr().unwrap());
+        drop(closed);
+        env.set("PYTHON_AI_URL", &url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+        let (status, _, _) = call_json(&app, add_doc()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let retries = logs.events("gateway.retry");
+        assert_eq!(retries.len(), MAX_RETRIES - 1);
+        assert!(retries
+            .iter()
+            .all(|retry| retry["status"] == "network_error"));
+    }
+
+    #[actix_web::test]
+    async fn poisoned_ask_cache_recovers_as_a_miss() {
+        let mut env = TestEnv::lock().await;
+        env.set("GATEWAY_ASK_CACHE_TTL_MS", "60000");
+        let cache = AskCache::from_env();
+        let answer = |text: &str| PythonAskResponse {
+            answer: text.to_string(),
+            citations: Vec::new(),
+            model: None,
+            backend_version: None,
+        };
+        cache.insert("before", &answer("stale"));
+        std::thread::scope(|scope| {
+            let poisoner = scope.spawn(|| {
+                let _entries = cache.entries.lock().unwrap();
+                panic!("poison the cache lock");
+            });
+            assert!(poisoner.join().is_err());
+        });
+        assert!(cache.entries.is_poisoned());
+        let logs = LogCapture::start();
+
+        assert!(cache.get("before").is_none());
+        let recovered = logs.events("gateway.cache_lock_recovered");
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0]["discarded_entries"], 1);
+        assert!(!cache.entries.is_poisoned());
+
+        cache.insert("after", &answer("fresh"));
+        assert_eq!(cache.get("after").unwrap().answer, "fresh");
+    }
+
+    #[actix_web::test]
+    async fn normalize_query_collapses_unicode_whitespace_and_optionally_case() {
+        let mut env = TestEnv::lock().await;
+        env.set("GATEWAY_QUERY_IGNORE_CASE", "false");
+        assert_eq!(
+            normalize_query(" \tWhat\u{a0}is\u{3000}\u{3000}Rust?\n"),
+            "What is Rust?"
+        );
+        assert_eq!(normalize_query("What Is RUST?"), "What Is RUST?");
+        assert_ne!(normalize_query("ＲＵＳＴ"), normalize_query("RUST"));
+
+        env.set("GATEWAY_QUERY_IGNORE_CASE", "true");
+        assert_eq!(normalize_query("What Is RUST?"), "what is rust?");
+        assert_eq!(normalize_query("ÉCOLE"), "école");
+        assert_ne!(normalize_query("ＲＵＳＴ"), normalize_query("rust"));
+    }
+
+    #[actix_web::test]
+    async fn retry_backoff_doubles_up_to_the_ceiling() {
+        let mut env = TestEnv::lock().await;
+        env.set("GATEWAY_MAX_BACKOFF_MS", "1000");
+        assert_eq!(retry_backoff(0), Duration::from_millis(BASE_BACKOFF_MS));
+        assert_eq!(retry_backoff(1), Duration::from_millis(2 * BASE_BACKOFF_MS));
+        assert_eq!(retry_backoff(2), Duration::from_millis(4 * BASE_BACKOFF_MS));
+        for attempt in [10, 63, 64, usize::MAX] {
+            assert_eq!(
+                retry_backoff(attempt),
+                Duration::from_millis(1000),
+                "{}",
+                attempt
+            );
+        }
+
+        env.set("GATEWAY_MAX_BACKOFF_MS", "1");
+        for attempt in [0, 10, 64, usize::MAX] {
+            assert_eq!(
+                retry_backoff(attempt),
+                Duration::from_millis(1),
+                "{}",
+                attempt
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn backend_model_and_version_are_relayed_only_when_reported() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| match req.json()["query"].as_str() {
+            Some("versioned") => MockReply::json(
+                200,
+                json!({
+                    "answer": "42",
+                    "citations": [],
+                    "model": "m1",
+                    "backend_version": "2026.10.1",
+                }),
+            ),
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+
+        let ask = post_json("/api/ask", json!({ "query": "versioned" }));
+        let (status, _, body) = call_json(&app, ask.to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["model"], "m1");
+        assert_eq!(body["backend_version"], "2026.10.1");
+        assert_eq!(logs.events("gateway.request")[0]["model"], "m1");
+
+        let ask = post_json("/api/ask", json!({ "query": "plain" }));
+        let (status, _, body) = call_json(&app, ask.to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.get("model").is_none());
+        assert!(body.get("backend_version").is_none());
+    }
+
+    #[actix_web::test]
+    async fn raw_queries_never_reach_the_logs() {
+        const QUERY: &str = "Is my  XYZZY diagnosis serious?";
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, earlier| match req.path.as_str() {
+            "/ask" if earlier % 2 == 0 => MockReply::json(500, json!({ "detail": QUERY })),
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_BACKOFF_MS", "1");
+
+        for hash in ["false", "true"] {
+            env.set("GATEWAY_LOG_QUERY_HASH", hash);
+            let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+            let logs = LogCapture::start();
+            let ask = post_json("/api/ask", json!({ "query": QUERY }));
+            let (status, _, _) = call_json(&app, ask.to_request()).await;
+            assert_eq!(status, StatusCode::OK);
+            let invalid = post_json("/api/ask", json!({ "query": QUERY, "seed": MAX_SEED + 1 }));
+            let (status, _, _) = call_json(&app, invalid.to_request()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+
+            assert!(!logs.events("gateway.request").is_empty());
+            assert!(!logs.text().contains("XYZZY"), "hash={}", hash);
+            assert_eq!(
+                logs.events("gateway.retry")[0]["body_snippet"],
+                r#"{"detail":"[redacted]"}"#
+            );
+            let summary = &logs.events("gateway.request")[0]["request"];
+            assert_eq!(summary["query_length"], QUERY.len());
+            if hash == "true" {
+                let normalized = format!("{:x}", Sha256::digest("Is my XYZZY diagnosis serious?"));
+                assert_eq!(summary["query_sha256"], normalized);
+            } else {
+                assert!(summary.get("query_sha256").is_none());
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn validate_doc_passes_the_backend_verdict_through() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| match req.json()["text"].as_str() {
+            Some("seen before") => {
+                MockReply::json(200, json!({ "valid": false, "issues": ["duplicate"] }))
+            }
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let validate =
+            |text: &str| post_json("/api/add_doc/validate", json!({ "text": text })).to_request();
+
+        let (status, _, body) = call_json(&app, validate("seen before")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["valid"], false);
+        assert_eq!(body["issues"], json!(["duplicate"]));
+
+        let (status, _, body) = call_json(&app, validate("brand new")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["valid"], true);
+        assert_eq!(body["issues"], json!([]));
+        assert_eq!(
+            python.requests("/validate_doc")[1].json()["text"],
+            "brand new"
+        );
+        assert_eq!(python.hits("/add_doc"), 0);
+    }
+
+    #[actix_web::test]
+    async fn get_requests_are_retried_after_a_server_error() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, earlier| match req.path.as_str() {
+            "/list_docs" if earlier == 0 => MockReply::json(500, json!({ "detail": "boom" })),
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_BACKOFF_MS", "1");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+
+        let (status, _, body) = call_json(&app, get("/api/docs").to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["documents"][0]["document_id"], "d1");
+        assert_eq!(python.hits("/list_docs"), 2);
+        let retries = logs.events("gateway.retry");
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0]["method"], "GET");
+        assert_eq!(retries[0]["status"], 500);
+    }
+
+    #[actix_web::test]
+    async fn cache_bypass_forces_a_backend_call_and_refreshes_the_entry() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|_, earlier| {
+            MockReply::json(
+                200,
+                json!({ "answer": format!("answer-{}", earlier), "citations": [] }),
+            )
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_ASK_CACHE_TTL_MS", "60000");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+        let ask = |header: Option<(&'static str, &'static str)>| {
+            let mut req = post_json("/api/ask", json!({ "query": "hi" }));
+            if let Some(header) = header {
+                req = req.insert_header(header);
+            }
+            req.to_request()
+        };
+
+        let (_, _, body) = call_json(&app, ask(None)).await;
+        assert_eq!(body["answer"], "answer-0");
+        let (_, _, body) = call_json(&app, ask(None)).await;
+        assert_eq!(body["answer"], "answer-0");
+        assert_eq!(python.hits("/ask"), 1);
+
+        for (header, answer) in [
+            (("Cache-Control", "no-cache"), "answer-1"),
+            ((BYPASS_CACHE_HEADER, "true"), "answer-2"),
+        ] {
+            let (_, _, body) = call_json(&app, ask(Some(header))).await;
+            assert_eq!(body["answer"], answer);
+            let (_, _, body) = call_json(&app, ask(None)).await;
+            assert_eq!(
+                body["answer"], answer,
+                "bypass must refresh the cached entry"
+            );
+        }
+        assert_eq!(python.hits("/ask"), 3);
+        let statuses: Vec<_> = logs
+            .events("gateway.request")
+            .iter()
+            .map(|event| event["cache"].clone())
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                json!("miss"),
+                json!("hit"),
+                json!("bypass"),
+                json!("hit"),
+                json!("bypass"),
+                json!("hit")
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn server_error_bodies_are_logged_truncated_and_scrubbed() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|_, _| MockReply {
+            status: 500,
+            body: "Traceback:\n\tKeyError\u{7}: 'index' while loading".to_string(),
+            headers: Vec::new(),
+            delay: Duration::ZERO,
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_BACKOFF_MS", "1")
+            .set("ERROR_BODY_LOG_CHARS", "24");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+        let add_doc = || post_json("/api/add_doc", json!({ "text": "doc" })).to_request();
+
+        let (status, _, _) = call_json(&app, add_doc()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let retry = &logs.events("gateway.retry")[0];
+        assert_eq!(retry["status"], 500);
+        assert_eq!(retry["body_snippet"], "Traceback:  KeyError : '…");
+
+        env.set("ERROR_BODY_LOG_CHARS", "0");
+        let logs = LogCapture::start();
+        call_json(&app, add_doc()).await;
+        assert!(logs.events("gateway.retry")[0]["body_snippet"].is_null());
+    }
+
+    #[actix_web::test]
+    async fn retry_deadline_stops_retrying_before_max_retries() {
+        let mut env = TestEnv::lock().await;
+        let python =
+            MockPython::start(|_, _| MockReply::json(500, json!({ "detail": "boom" })).delayed(50))
+                .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_BACKOFF_MS", "1000")
+            .set("GATEWAY_RETRY_DEADLINE_MS", "300");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+
+        let started = Instant::now();
+        let add_doc = post_json("/api/add_doc", json!({ "text": "doc" }));
+        let (status, _, body) = call_json(&app, add_doc.to_request()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(started.elapsed() < Duration::from_millis(600));
+        assert!(python.hits("/add_doc") < MAX_RETRIES);
+        assert_eq!(body["last_error"], "Retry deadline exceeded");
+        assert_eq!(logs.events("gateway.retry_deadline_exceeded").len(), 1);
+    }
+
+    #[test]
+    fn log_sequence_numbers_are_unique_and_increasing() {
+        let per_thread: Vec<Vec<u64>> = std::thread::scope(|scope| {
+            let loggers: Vec<_> = (0..8)
+                .map(|thread| {
+                    scope.spawn(move || {
+                        let logs = LogCapture::start();
+                        for n in 0..50 {
+                            log_gateway_event("test.seq", json!({ "thread": thread, "n": n }));
+                        }
+                        let entries: Vec<Value> = serde_json::from_str(&logs.text()).unwrap();
+                        entries
+                            .iter()
+                            .map(|entry| entry["seq"].as_u64().unwrap())
+                            .collect()
+                    })
+                })
+                .collect();
+            loggers
+                .into_iter()
+                .map(|logger| logger.join().unwrap())
+                .collect()
+        });
+
+        for seqs in &per_thread {
+            assert_eq!(seqs.len(), 50);
+            assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        let unique: HashSet<u64> = per_thread.iter().flatten().copied().collect();
+        assert_eq!(unique.len(), 8 * 50);
+    }
+
+    #[actix_web::test]
+    async fn matching_client_errors_fail_over_to_the_next_backend() {
+        let mut env = TestEnv::lock().await;
+        let overloaded =
+            MockPython::start(|_, _| MockReply::json(400, json!({ "error": "model_overloaded" })))
+                .await;
+        let healthy = MockPython::start(|req, _| python_reply(req)).await;
+        env.set(
+            "PYTHON_AI_URL",
+            &format!("{},{}", overloaded.url, healthy.url),
+        )
+        .set("GATEWAY_MAX_BACKOFF_MS", "1")
+        .set(
+            "FAILOVER_ON_ERROR_PATTERNS",
+            "quota_exceeded, model_overloaded",
+        );
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+        let add_doc = || post_json("/api/add_doc", json!({ "text": "doc" })).to_request();
+
+        BACKEND_CURSOR.store(0, Ordering::Relaxed);
+        let (status, _, body) = call_json(&app, add_doc()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["document_id"], "doc-1");
+        assert_eq!(
+            (overloaded.hits("/add_doc"), healthy.hits("/add_doc")),
+            (1, 1)
+        );
+        let retry = &logs.events("gateway.retry")[0];
+        assert_eq!(retry["backend"], overloaded.url.as_str());
+        assert_eq!(retry["status"], 400);
+
+        env.set("FAILOVER_ON_ERROR_PATTERNS", "quota_exceeded");
+        BACKEND_CURSOR.store(0, Ordering::Relaxed);
+        let (status, _, _) = call_json(&app, add_doc()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            (overloaded.hits("/add_doc"), healthy.hits("/add_doc")),
+            (2, 1)
+        );
+    }
+
+    #[actix_web::test]
+    async fn key_profiles_fill_omitted_fields_and_are_validated() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        let profiles = json!({
+            TEST_API_KEY: { "model": "profile-model", "language": "de", "temperature": 0.2 },
+        });
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_KEY_PROFILES", &profiles.to_string());
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+
+        let ask = post_json("/api/ask", json!({ "query": "hi" }));
+        let (status, _, _) = call_json(&app, ask.to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        let ask = post_json(
+            "/api/ask",
+            json!({ "query": "hi", "model": "client-model" }),
+        );
+        let (status, _, _) = call_json(&app, ask.to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        let forwarded: Vec<Value> = python.requests("/ask").iter().map(|r| r.json()).collect();
+        assert_eq!(forwarded[0]["model"], "profile-model");
+        assert_eq!(forwarded[0]["language"], "de");
+        assert_eq!(forwarded[0]["temperature"], 0.2);
+        assert_eq!(forwarded[1]["model"], "client-model");
+        assert_eq!(forwarded[1]["language"], "de");
+
+        let long_session = "s".repeat(MAX_SESSION_ID_LEN + 1);
+        let profiles = json!({ TEST_API_KEY: { "session_id": long_session } });
+        env.set("GATEWAY_KEY_PROFILES", &profiles.to_string());
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let ask = post_json("/api/ask", json!({ "query": "hi" }));
+        let (status, _, body) = call_json(&app, ask.to_request()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "session_id");
+        assert_eq!(python.hits("/ask"), 2);
+
+        let profiles = json!({ TEST_API_KEY: { "seed": "not a number" } });
+        env.set("GATEWAY_KEY_PROFILES", &profiles.to_string());
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+        let ask = post_json("/api/ask", json!({ "query": "hi" }));
+        let (status, _, _) = call_json(&app, ask.to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(python.requests("/ask")[2].json().get("seed").is_none());
+        assert_eq!(logs.events("gateway.config").len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn invalid_json_bodies_get_json_errors() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let raw = |uri: &str, content_type: &str, body: &'static str| {
+            TestRequest::post()
+                .uri(uri)
+                .insert_header((API_KEY_HEADER, TEST_API_KEY))
+                .insert_header((header::CONTENT_TYPE, content_type.to_owned()))
+                .set_payload(body)
+                .to_request()
+        };
+
+        for (uri, required, incomplete) in [
+            ("/api/ask", "query", r#"{"top_k": 3}"#),
+            ("/api/add_doc", "text", r#"{"metadata": {"source": "x"}}"#),
+        ] {
+            let logs = LogCapture::start();
+            let cases = [
+                ("", "malformed_json"),
+                ("query=hi", "malformed_json"),
+                (incomplete, "invalid_field"),
+            ];
+            for (index, (body, reason)) in cases.into_iter().enumerate() {
+                let (status, _, body) = call_json(&app, raw(uri, "application/json", body)).await;
+                assert_eq!(status, StatusCode::BAD_REQUEST, "{} {}", uri, reason);
+                assert!(body["error"].is_string(), "{} {}", uri, reason);
+                let logged = &logs.events("gateway.invalid_body")[index];
+                assert_eq!(logged["reason"], reason, "{}", uri);
+                assert_eq!(logged["path"], uri);
+            }
+            let (_, _, body) = call_json(&app, raw(uri, "application/json", incomplete)).await;
+            let missing = format!("missing field `{}`", required);
+            assert!(
+                body["error"].as_str().unwrap().contains(&missing),
+                "{}",
+                uri
+            );
+
+            let (status, _, body) =
+                call_json(&app, raw(uri, "text/plain", r#"{"text": "hi"}"#)).await;
+            assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE, "{}", uri);
+            assert!(body["error"].is_string());
+            assert_eq!(logs.events("gateway.unsupported_media_type").len(), 1);
+        }
+        assert_eq!(python.hits("/ask"), 0);
+        assert_eq!(python.hits("/add_doc"), 0);
+    }
+
+    #[actix_web::test]
+    async fn synthetic_check_reports_answer_and_latency_failures() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, earlier| match earlier {
+            0 => python_reply(req),
+            1 => MockReply::json(200, json!({ "answer": " ", "citations": [] })),
+            _ => python_reply(req).delayed(50),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_ADMIN_KEY", "admin-secret")
+            .set("GATEWAY_CANARY_QUERY", "canary?")
+            .set("GATEWAY_CANARY_MAX_LATENCY_MS", "1000");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let check = || {
+            get("/api/admin/synthetic_check")
+                .insert_header((ADMIN_KEY_HEADER, "admin-secret"))
+                .to_request()
+        };
+
+        let (status, _, body) = call_json(&app, check()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["passed"], true);
+        assert_eq!(body["citations"], 1);
+        assert_eq!(python.requests("/ask")[0].json()["query"], "canary?");
+
+        let (status, _, body) = call_json(&app, check()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["failures"], json!(["answer was empty"]));
+
+        env.set("GATEWAY_CANARY_MAX_LATENCY_MS", "10");
+        let (status, _, body) = call_json(&app, check()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["failures"][0]
+            .as_str()
+            .unwrap()
+            .contains("exceeded threshold 10ms"));
+
+        let unauthenticated = get("/api/admin/synthetic_check").to_request();
+        let (status, _, _) = call_json(&app, unauthenticated).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(python.hits("/ask"), 3);
+    }
+
+    #[actix_web::test]
+    async fn duplicate_citations_are_dropped_and_counted_when_enabled() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|_, _| {
+            let citation = |source: &str, text: &str| json!({ "source": source, "text": text });
+            MockReply::json(
+                200,
+                json!({
+                    "answer": "42",
+                    "citations": [
+                        citation("/store/a", "one"),
+                        citation("/store/a", "one"),
+                        citation("/store/a", "two"),
+                        citation("/store/b", "one"),
+                        citation("/store/a", "one"),
+                    ],
+                }),
+            )
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("DEDUP_CITATIONS", "false");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let ask = || post_json("/api/ask", json!({ "query": "hi" })).to_request();
+
+        let (_, _, body) = call_json(&app, ask()).await;
+        assert_eq!(body["citations"].as_array().unwrap().len(), 5);
+        assert!(body.get("citations_deduped").is_none());
+
+        env.set("DEDUP_CITATIONS", "true");
+        let (_, _, body) = call_json(&app, ask()).await;
+        let kept: Vec<_> = body["citations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|citation| (citation["source"].clone(), citation["text"].clone()))
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                (json!("/store/a"), json!("one")),
+                (json!("/store/a"), json!("two")),
+                (json!("/store/b"), json!("one")),
+            ]
+        );
+        assert_eq!(body["citations_deduped"], 2);
+    }
+
+    #[actix_web::test]
+    async fn seeds_are_forwarded_up_to_the_maximum() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+
+        let ask = post_json("/api/ask", json!({ "query": "hi", "seed": MAX_SEED }));
+        let (status, _, _) = call_json(&app, ask.to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        let by_query = get("/api/ask?query=hi&seed=7").to_request();
+        let (status, _, _) = call_json(&app, by_query).await;
+        assert_eq!(status, StatusCode::OK);
+        let forwarded = python.requests("/ask");
+        assert_eq!(forwarded[0].json()["seed"], MAX_SEED);
+        assert_eq!(forwarded[1].json()["seed"], 7);
+
+        let ask = post_json("/api/ask", json!({ "query": "hi", "seed": MAX_SEED + 1 }));
+        let (status, _, body) = call_json(&app, ask.to_request()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "seed");
+        assert_eq!(body["constraint"], "max_value");
+        assert_eq!(python.hits("/ask"), 2);
+    }
+
+    #[test]
+    fn accept_headers_admit_supported_types_and_wildcards() {
+        for accept in [
+            None,
+            Some(""),
+            Some("application/json"),
+            Some("*/*"),
+            Some("application/*"),
+            Some("text/html, application/json;q=0.5"),
+            Some("Application/JSON; charset=utf-8"),
+        ] {
+            assert!(accepts_supported_type(accept), "{:?}", accept);
+        }
+        for accept in [
+            Some("application/xml"),
+            Some("text/html, application/xhtml+xml"),
+            Some("application/json;q=0"),
+            Some("*/*; q=0.0, application/xml"),
+        ] {
+            assert!(!accepts_supported_type(accept), "{:?}", accept);
+        }
+    }
+
+    #[actix_web::test]
+    async fn unacceptable_responses_are_refused_with_406() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+        let ask = |accept: &str| {
+            post_json("/api/ask", json!({ "query": "hi" }))
+                .insert_header((header::ACCEPT, accept.to_owned()))
+                .to_request()
+        };
+
+        let (status, _, body) = call_json(&app, ask("application/xml")).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+        assert!(body["error"].is_string());
+        assert_eq!(logs.events("gateway.not_acceptable").len(), 1);
+        assert_eq!(python.hits("/ask"), 0);
+
+        let (status, _, _) = call_json(&app, ask("application/json")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn app_stats_count_per_app_with_an_overflow_bucket() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_TRACKED_APPS", "2");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+
+        for app_id in ["web", "mobile", "web", "cli", "batch", "mobile"] {
+            let ask = post_json("/api/ask", json!({ "query": "hi" }))
+                .insert_header((APP_ID_HEADER, app_id))
+                .to_request();
+            let (status, _, _) = call_json(&app, ask).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (_, _, unlabelled) = call_json(
+            &app,
+            post_json("/api/ask", json!({ "query": "hi" })).to_request(),
+        )
+        .await;
+        assert!(unlabelled["answer"].is_string());
+
+        let (status, _, stats) = call_json(&app, get("/api/stats").to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            stats["apps"],
+            json!({ "web": 2, "mobile": 2, APP_OVERFLOW_BUCKET: 2 })
+        );
+        assert_eq!(stats["max_tracked_apps"], 2);
+        assert_eq!(stats["overflow_bucket"], APP_OVERFLOW_BUCKET);
+    }
+
+    #[actix_web::test]
+    async fn responses_are_gzipped_when_the_client_accepts_it() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url);
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+
+        let ask = post_json("/api/ask", json!({ "query": "hi" }))
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_request();
+        let resp = test::call_service(&app, ask).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        let compressed = test::read_body(resp).await;
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        let body: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(body["answer"], "42");
+
+        let plain = post_json("/api/ask", json!({ "query": "hi" })).to_request();
+        let resp = test::call_service(&app, plain).await;
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[actix_web::test]
+    async fn large_upstream_bodies_are_gzipped_above_the_threshold() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_UPSTREAM_GZIP_MIN_BYTES", "64");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+
+        let small = json!({ "text": "short" });
+        let (plain, gzipped) = encode_upstream_body(&small).unwrap();
+        assert!(!gzipped);
+        assert_eq!(plain, serde_json::to_vec(&small).unwrap());
+        let large = json!({ "text": "a long document ".repeat(20) });
+        let (compressed, gzipped) = encode_upstream_body(&large).unwrap();
+        assert!(gzipped);
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, serde_json::to_vec(&large).unwrap());
+
+        for doc in [&small, &large] {
+            let (status, _, _) =
+                call_json(&app, post_json("/api/add_doc", doc.clone()).to_request()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        // The mock server decodes request bodies, so both arrive as plain JSON.
+        let received = python.requests("/add_doc");
+        assert!(received[0].headers.get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(
+            received[1].headers.get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(received[1].json()["text"], large["text"]);
+    }
+
+    #[actix_web::test]
+    async fn max_answer_chars_is_forwarded_with_defaults_and_clamping() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req)).await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_ANSWER_CHARS_CAP", "500")
+            .set("GATEWAY_DEFAULT_MAX_ANSWER_CHARS", "");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let forwarded = |body: Value| {
+            let app = &app;
+            let python = &python;
+            async move {
+                let (status, _, _) = call_json(app, post_json("/api/ask", body).to_request()).await;
+                assert_eq!(status, StatusCode::OK);
+                python.requests("/ask").last().unwrap().json()["max_answer_chars"].clone()
+            }
+        };
+
+        assert_eq!(
+            forwarded(json!({ "query": "a", "max_answer_chars": 200 })).await,
+            200
+        );
+        assert_eq!(
+            forwarded(json!({ "query": "b", "max_answer_chars": 9000 })).await,
+            500
+        );
+        assert_eq!(
+            forwarded(json!({ "query": "c", "max_answer_chars": 0 })).await,
+            1
+        );
+        assert!(forwarded(json!({ "query": "d" })).await.is_null());
+
+        env.set("GATEWAY_DEFAULT_MAX_ANSWER_CHARS", "300");
+        assert_eq!(forwarded(json!({ "query": "e" })).await, 300);
+        assert_eq!(
+            forwarded(json!({ "query": "f", "max_answer_chars": 120 })).await,
+            120
+        );
+    }
+
+    #[actix_web::test]
+    async fn requests_queued_for_an_upstream_permit_report_their_wait() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| python_reply(req).delayed(200)).await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_CONCURRENT_UPSTREAM", "1");
+        let app = Rc::new(test::init_service(gateway_app(&GatewayState::from_env(), false)).await);
+        let logs = LogCapture::start();
+
+        let asks = ["first", "second"]
+            .iter()
+            .map(|query| post_json("/api/ask", json!({ "query": query })).to_request())
+            .collect();
+        for (status, _) in call_concurrently(&app, asks).await {
+            assert_eq!(status, StatusCode::OK);
+        }
+        let mut waits: Vec<u64> = logs
+            .events("gateway.request")
+            .iter()
+            .map(|event| event["queue_wait_ms"].as_u64().unwrap())
+            .collect();
+        waits.sort_unstable();
+        assert!(waits[0] < 50, "{:?}", waits);
+        assert!(waits[1] >= 150, "{:?}", waits);
+
+        let (_, _, stats) = call_json(&*app, get("/api/stats").to_request()).await;
+        assert_eq!(stats["queue_wait_ms"]["samples"], 2);
+        assert_eq!(stats["queue_wait_ms"]["p99"], waits[1]);
+    }
+
+    #[actix_web::test]
+    async fn asks_are_shed_while_the_backend_reports_a_deep_queue() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, earlier| {
+            let depth = if earlier == 0 { 12 } else { 0 };
+            python_reply(req).header(BACKEND_QUEUE_DEPTH_HEADER, depth)
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("BACKEND_QUEUE_SHED_THRESHOLD", "10");
+        *BACKEND_QUEUE_DEPTH.lock().unwrap() = None;
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+        let ask = || post_json("/api/ask", json!({ "query": "hi" })).to_request();
+
+        let (status, _, _) = call_json(&app, ask()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, headers, body) = call_json(&app, ask()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "1");
+        assert_eq!(body["queue_depth"], 12);
+        assert_eq!(logs.events("gateway.load_shed")[0]["threshold"], 10);
+        assert_eq!(python.hits("/ask"), 1);
+
+        // Once the reading goes stale a request gets through and refreshes it.
+        *BACKEND_QUEUE_DEPTH.lock().unwrap() = Some((
+            12,
+            Instant::now() - Duration::from_millis(BACKEND_QUEUE_DEPTH_TTL_MS),
+        ));
+        let (status, _, _) = call_json(&app, ask()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) = call_json(&app, ask()).await;
+        assert_eq!(status, StatusCode::OK);
+        *BACKEND_QUEUE_DEPTH.lock().unwrap() = None;
+    }
+
+    #[actix_web::test]
+    async fn response_meta_describes_the_instance_when_enabled() {
+        let mut env = TestEnv::lock().await;
+        env.set("STAMP_RESPONSE_META", "false");
+        assert!(response_meta().is_none());
+
+        env.set("STAMP_RESPONSE_META", "true")
+            .set("GATEWAY_INSTANCE_ID", "gw-7")
+            .set("GATEWAY_REGION", "eu-west-1")
+            .set("GATEWAY_SIGNING_SECRET", "first");
+        let meta = response_meta().unwrap();
+        assert_eq!(meta["gateway_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(meta["instance_id"], "gw-7");
+        assert_eq!(meta["region"], "eu-west-1");
+        let config_hash = meta["config_hash"].as_str().unwrap().to_owned();
+        assert_eq!(config_hash.len(), 16);
+
+        env.set("GATEWAY_SIGNING_SECRET", "second");
+        assert_eq!(
+            response_meta().unwrap()["config_hash"],
+            config_hash.as_str()
+        );
+        env.set("GATEWAY_REGION", "us-east-1");
+        assert_ne!(
+            response_meta().unwrap()["config_hash"],
+            config_hash.as_str()
+        );
+    }
+
+    #[actix_web::test]
+    async fn retry_stats_count_retries_recoveries_and_exhaustion() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, earlier| match earlier {
+            0 | 3..=5 => MockReply::json(500, json!({ "detail": "boom" })),
+            _ => python_reply(req),
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_BACKOFF_MS", "1");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let validate = || post_json("/api/add_doc/validate", json!({ "text": "doc" })).to_request();
+        let stats = || {
+            RETRY_STATS
+                .lock()
+                .unwrap()
+                .get(PYTHON_VALIDATE_DOC_ENDPOINT)
+                .copied()
+                .unwrap_or_default()
+        };
+        let before = stats();
+
+        // Recovers on the second attempt, succeeds first time, then exhausts.
+        let (status, _, _) = call_json(&app, validate()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) = call_json(&app, validate()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) = call_json(&app, validate()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+        let after = stats();
+        assert_eq!(after.retries - before.retries, 1 + (MAX_RETRIES as u64 - 1));
+        assert_eq!(after.recovered - before.recovered, 1);
+        assert_eq!(after.exhausted - before.exhausted, 1);
+        let (_, _, status) = call_json(&app, get("/api/status").to_request()).await;
+        assert_eq!(
+            status["retries"][PYTHON_VALIDATE_DOC_ENDPOINT]["exhausted"],
+            after.exhausted
+        );
+    }
+
+    #[test]
+    fn openapi_documents_every_ask_and_add_doc_status() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let statuses = |path: &str| -> Vec<String> {
+            doc["paths"][path]["post"]["responses"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect()
+        };
+        let ask = statuses("/api/ask");
+        for status in [
+            "200", "400", "401", "406", "415", "422", "429", "499", "502", "503", "504",
+        ] {
+            assert!(ask.iter().any(|s| s == status), "/api/ask lacks {}", status);
+        }
+        let add_doc = statuses("/api/add_doc");
+        for status in [
+            "200", "400", "401", "406", "415", "422", "429", "502", "503", "504",
+        ] {
+            assert!(
+                add_doc.iter().any(|s| s == status),
+                "/api/add_doc lacks {}",
+                status
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn undecodable_answers_do_not_leak_echoed_queries_into_logs() {
+        let mut env = TestEnv::lock().await;
+        let python = MockPython::start(|req, _| {
+            // The echoed query lands in a field that must be a list.
+            MockReply::json(
+                200,
+                json!({ "answer": "42", "citations": req.json()["query"] }),
+            )
+        })
+        .await;
+        env.set("PYTHON_AI_URL", &python.url)
+            .set("GATEWAY_MAX_BACKOFF_MS", "1");
+        let app = test::init_service(gateway_app(&GatewayState::from_env(), false)).await;
+        let logs = LogCapture::start();
+
+        let ask = post_json("/api/ask", json!({ "query": "my XYZZY symptoms" }));
+        let (status, _, body) = call_json(&app, ask.to_request()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(python.hits("/ask"), 2);
+        let retry = &logs.events("gateway.retry")[0];
+        assert_eq!(retry["reason"], "decode_error");
+        assert!(retry["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Failed to parse upstream response: unexpected shape at line 1"));
+        assert!(!logs.text().contains("XYZZY"));
+        assert!(!body.to_string().contains("XYZZY"));
+    }
+
+    #[actix_web::test]
+    async fn queue_wait_runs_from_when_the_handler_started() {
+        let mut env = TestEnv::lock().await;
+        env.set("GATEWAY_MAX_CONCURRENT_UPSTREAM", "1");
+        let limiter = UpstreamLimiter::from_env();
+
+        let received = Instant::now() - Duration::from_millis(120);
+        let (permit, wait_ms) = limiter.acquire(received).await;
+        assert!(permit.is_some());
+        assert!(wait_ms.unwrap() >= 120, "{:?}", wait_ms);
+        drop(permit);
+
+        let (_, wait_ms) = limiter.acquire(Instant::now()).await;
+        assert!(wait_ms.unwrap() < 50, "{:?}", wait_ms);
+        assert_eq!(limiter.queue_wait_percentiles().unwrap()["samples"], 2);
+    }
 }